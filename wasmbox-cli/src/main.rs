@@ -1,7 +1,10 @@
 use anyhow::{anyhow, Result};
 use clap::{Parser, Subcommand};
-use std::{io::BufRead, time::SystemTime};
-use wasmbox_host::{prepare_module, WasmBoxHost};
+use std::{fs::File, io::BufRead, time::SystemTime};
+use wasmbox_host::{
+    prepare_module, verify_journal, JournalOptions, Snapshot, WasmBoxHost, WasmBoxModule,
+    WasmBoxOptions,
+};
 
 #[derive(Parser)]
 struct Opts {
@@ -31,6 +34,38 @@ enum Command {
         /// !!clock command is provided.
         #[clap(long)]
         freeze_time: bool,
+
+        /// Seed for the module's random number generator, as 64 hexadecimal digits.
+        #[clap(long, value_parser = parse_seed)]
+        seed: Option<[u8; 32]>,
+
+        /// Record every message, clock update and restored snapshot to this file, along with
+        /// the module's state after each, so that the session can be checked with the verify
+        /// command.
+        #[clap(long)]
+        journal: Option<String>,
+    },
+    /// Replay a journal twice and check that the module reaches the same state both times, and
+    /// the state recorded in the journal.
+    Verify {
+        /// The path to a .wasm file, or to a compiled module if --compiled is given.
+        module_filename: String,
+
+        /// The path to a journal (as recorded by run --journal.)
+        journal_filename: String,
+
+        /// Snapshot the journal starts from. If omitted, the journal is assumed to start from
+        /// a newly initialized module.
+        #[clap(long)]
+        snapshot: Option<String>,
+
+        /// Seed the module was run with, as 64 hexadecimal digits.
+        #[clap(long, value_parser = parse_seed)]
+        seed: Option<[u8; 32]>,
+
+        /// The module is a compiled module (as output by the compile command.)
+        #[clap(long)]
+        compiled: bool,
     },
 }
 
@@ -44,7 +79,7 @@ enum InteractiveCommand {
 impl InteractiveCommand {
     pub fn parse(line: &str) -> Result<InteractiveCommand> {
         if let Some(command_line) = line.strip_prefix("!!") {
-            let mut command_parts = command_line.split_whitespace().into_iter();
+            let mut command_parts = command_line.split_whitespace();
             if let Some(command) = command_parts.next() {
                 match command {
                     "snapshot" => Ok(InteractiveCommand::SaveSnapshot),
@@ -67,7 +102,7 @@ impl InteractiveCommand {
                     cmd => Err(anyhow!("Unknown command {}", cmd))
                 }
             } else {
-                return Err(anyhow!("Expected command to follow '!!'"));
+                Err(anyhow!("Expected command to follow '!!'"))
             }
         } else {
             Ok(InteractiveCommand::SendMessage(line.to_string()))
@@ -75,6 +110,18 @@ impl InteractiveCommand {
     }
 }
 
+fn parse_seed(seed: &str) -> Result<[u8; 32]> {
+    if seed.len() != 64 || !seed.is_ascii() {
+        return Err(anyhow!("Expected 64 hexadecimal digits."));
+    }
+
+    let mut result = [0; 32];
+    for (i, byte) in result.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&seed[2 * i..2 * i + 2], 16)?;
+    }
+    Ok(result)
+}
+
 fn current_time() -> u64 {
     SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).expect("Invalid system time.").as_millis() as u64
 }
@@ -94,16 +141,16 @@ fn do_command(
             println!("Froze to {}", filename);
         }
         InteractiveCommand::RestoreSnapshot(filename) => {
-            wasmbox.restore_snapshot_from_file(&filename)?;
+            wasmbox.restore_snapshot_from_file(filename)?;
             println!("Restored from {}", filename);
         }
         InteractiveCommand::SendMessage(line) => {
-            wasmbox.message(line);
+            wasmbox.message(line)?;
         }
         InteractiveCommand::UpdateClock(time) => {
             let time = time.unwrap_or_else(current_time);
 
-            wasmbox.set_time(time);
+            wasmbox.set_time(time)?;
         }
     }
 
@@ -124,18 +171,29 @@ fn main() -> anyhow::Result<()> {
             compiled_module_filename,
             wasm_filename,
             freeze_time,
+            seed,
+            journal,
         } => {
-            let mut mybox = if let Some(compiled_module_filename) = compiled_module_filename {
-                WasmBoxHost::from_compiled_module(&compiled_module_filename, |st: String| {
-                    println!("==> [{}]", st)
-                })?
+            let module = if let Some(compiled_module_filename) = compiled_module_filename {
+                WasmBoxModule::from_compiled_module(&compiled_module_filename)?
             } else if let Some(wasm_filename) = wasm_filename {
-                WasmBoxHost::from_wasm_file(&wasm_filename, |st: String| println!("==> [{}]", st))?
+                WasmBoxModule::from_wasm_file(&wasm_filename)?
             } else {
                 return Err(anyhow!(
                     "Either --wasm-filename or --compiled-module-filename must be given."
                 ));
             };
+            let options = WasmBoxOptions {
+                rng_seed: seed,
+                ..WasmBoxOptions::default()
+            };
+            let mut mybox = WasmBoxHost::new_with_options(&module, options, |st: String| {
+                println!("==> [{}]", st)
+            })?;
+            if let Some(journal) = journal {
+                let journal_options = JournalOptions { state_hashes: true };
+                mybox.set_journal_with_options(File::create(journal)?, journal_options)?;
+            }
 
             let stdin = std::io::stdin();
             let iterator = stdin.lock().lines();
@@ -152,13 +210,52 @@ fn main() -> anyhow::Result<()> {
                 };
 
                 if !freeze_time {
-                    mybox.set_time(current_time());
+                    if let Err(error) = mybox.set_time(current_time()) {
+                        println!("Error updating clock. {:?}", error);
+                    }
                 }
 
                 if let Err(error) = do_command(&mut mybox, &command) {
                     println!("Error running command. {:?}", error);
                 }
+
+                if mybox.is_finished() {
+                    println!("Guest finished.");
+                    break;
+                }
+            }
+        }
+        Command::Verify {
+            module_filename,
+            journal_filename,
+            snapshot,
+            seed,
+            compiled,
+        } => {
+            let module = if compiled {
+                WasmBoxModule::from_compiled_module(&module_filename)?
+            } else {
+                WasmBoxModule::from_wasm_file(&module_filename)?
+            };
+            let options = WasmBoxOptions {
+                rng_seed: seed,
+                ..WasmBoxOptions::default()
+            };
+            let snapshot = if let Some(snapshot) = snapshot {
+                Snapshot::from_file(&snapshot, &module)?
+            } else {
+                let mut initial: WasmBoxHost<String, String> =
+                    WasmBoxHost::new_with_output_queue(&module, options.clone())?;
+                initial.snapshot_state()?
+            };
+
+            let journal = File::open(journal_filename)?;
+            let verification =
+                verify_journal::<String, String>(&module, &snapshot, journal, options)?;
+            if let Some(divergence) = verification.divergence {
+                return Err(anyhow!("{}", divergence));
             }
+            println!("Replayed {} events deterministically.", verification.steps);
         }
     }
 
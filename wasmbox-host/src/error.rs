@@ -0,0 +1,107 @@
+use thiserror::Error;
+use wasmtime::Trap;
+
+/// An error from a `WasmBoxHost` or from loading the modules and snapshots it runs.
+#[derive(Error, Debug)]
+pub enum WasmBoxError {
+    /// The module could not be compiled or instantiated. This wraps the error reported by
+    /// wasmtime, whose API reports errors as `anyhow::Error`; it is kept as is so that its
+    /// chain of causes is preserved and can be inspected with `downcast_ref`.
+    #[error("Could not load module: {0:#}")]
+    Module(anyhow::Error),
+
+    /// The module is not valid WebAssembly (or WebAssembly text).
+    #[error("Invalid module: {0}")]
+    InvalidModule(String),
+
+    /// The module exports a name which wasmbox reserves for exporting its globals and
+    /// tables, so that they can be snapshotted.
+    #[error("The module exports {0}, which is reserved for exporting its state.")]
+    ReservedExport(String),
+
+    /// A file passed to `WasmBoxModule::from_compiled_module` was not written by
+    /// `prepare_module`.
+    #[error("{0} is not a module compiled by prepare_module.")]
+    NotCompiledModule(String),
+
+    /// The module does not export something the wasmbox ABI requires.
+    #[error("The module does not export {0}.")]
+    MissingExport(String),
+
+    /// The module exports something the wasmbox ABI requires, but with a type wasmbox can't
+    /// use.
+    #[error("The module's export {0} has the wrong type.")]
+    MistypedExport(String),
+
+    /// A snapshot can't be taken, because a table holds an element which a snapshot can't
+    /// represent: a function which the module doesn't export for snapshots, or an `externref`
+    /// other than null.
+    #[error("Element {index} of table {table} can't be saved in a snapshot.")]
+    UnsupportedTableElement { table: String, index: u32 },
+
+    /// A snapshot is corrupt, or can't be restored into the module.
+    #[error("Incompatible snapshot: {0}")]
+    IncompatibleSnapshot(String),
+
+    #[error("The guest ran out of fuel.")]
+    OutOfFuel,
+
+    #[error("The guest did not finish before its deadline.")]
+    Timeout,
+
+    /// The guest trapped after being refused memory beyond `max_memory_bytes`. A guest
+    /// refused memory usually aborts, so this is reported in place of a trap when the most
+    /// recent attempt to grow memory during the call was refused. The trap may still have
+    /// had another cause, if the guest carried on after the refusal.
+    #[error("The guest tried to grow its memory beyond the limit.")]
+    MemoryLimitExceeded,
+
+    /// The guest trapped after being refused table elements beyond `max_table_elements`,
+    /// reported in the same way as `MemoryLimitExceeded`.
+    #[error("The guest tried to grow a table beyond the limit.")]
+    TableLimitExceeded,
+
+    /// The guest trapped, e.g. by panicking. The trap's `trace` gives the guest backtrace.
+    #[error("The guest trapped: {0}")]
+    Trap(#[from] Trap),
+
+    /// A message to or from the guest could not be (de)serialized.
+    #[error("Could not serialize message: {0}")]
+    Serialization(#[from] bincode::Error),
+
+    /// The guest broke the contract between host and guest, e.g. by passing an out-of-bounds
+    /// pointer or failing to allocate space for a message.
+    #[error("The guest violated the wasmbox ABI: {0}")]
+    Abi(String),
+
+    /// A message to or from the guest is larger than `WasmBoxOptions::max_message_bytes`.
+    #[error("Message of {len} bytes exceeds the limit of {max} bytes.")]
+    MessageTooLarge { len: usize, max: usize },
+
+    /// A journal is corrupt, or was recorded from a different module.
+    #[error("Invalid journal: {0}")]
+    InvalidJournal(String),
+
+    /// The host responded to a request which the guest has not made, or which has already
+    /// been responded to.
+    #[error("The guest has no outstanding request {0}.")]
+    UnknownRequest(u32),
+
+    /// An earlier message failed and left the guest in an unknown state. The box must be
+    /// restored from a snapshot before it accepts further messages.
+    #[error("The box is poisoned by an earlier failed message.")]
+    Poisoned,
+
+    /// The guest's `run` function has returned, so it accepts no further messages. Its
+    /// return value is available from `WasmBoxHost::exit_value`.
+    #[error("The guest has finished.")]
+    BoxFinished,
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    /// An unexpected failure in the WebAssembly runtime. Like `Module`, this wraps wasmtime's
+    /// `anyhow::Error`.
+    #[error("WebAssembly runtime error: {0:#}")]
+    Runtime(anyhow::Error),
+}
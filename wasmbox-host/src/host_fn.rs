@@ -0,0 +1,99 @@
+//! Functions which the host provides for guests to call by name.
+
+use crate::store::StoreData;
+use crate::{check_message_size, get_memory, get_u8_vec, WasmBoxError, EXT_FN_MALLOC};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+use wasmtime::{Caller, Extern};
+
+type HostFn = Arc<dyn Fn(&[u8]) -> Result<Vec<u8>, WasmBoxError> + Send + Sync>;
+
+/// A set of named functions which guests can call with `call_host`. Requests and responses
+/// are serialized with bincode, like messages.
+#[derive(Clone, Default)]
+pub struct HostFunctions {
+    functions: HashMap<String, HostFn>,
+}
+
+impl HostFunctions {
+    /// Register `function` under `name`, replacing any function already registered under it.
+    pub fn register<Request, Response, F>(&mut self, name: &str, function: F)
+    where
+        Request: DeserializeOwned,
+        Response: Serialize,
+        F: Fn(Request) -> Response + 'static + Send + Sync,
+    {
+        let function = move |request: &[u8]| {
+            let request: Request = bincode::deserialize(request)?;
+            Ok(bincode::serialize(&function(request))?)
+        };
+        self.functions.insert(name.to_string(), Arc::new(function));
+    }
+
+    fn get(&self, name: &str) -> Option<HostFn> {
+        self.functions.get(name).cloned()
+    }
+}
+
+impl Debug for HostFunctions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.functions.keys()).finish()
+    }
+}
+
+/// Handle a guest's call to a host function. The response is written to a buffer allocated
+/// with the guest's `wasmbox_malloc`, and its address and length are returned packed into the
+/// high and low halves of a `u64`. An empty response is returned without allocating.
+pub fn call_host<Output>(
+    caller: &mut Caller<'_, StoreData<Output>>,
+    (name_ptr, name_len): (u32, u32),
+    (request_ptr, request_len): (u32, u32),
+    max_message_bytes: Option<usize>,
+) -> Result<u64, WasmBoxError> {
+    check_message_size(request_len as usize, max_message_bytes)?;
+    let memory = get_memory(caller)?;
+
+    let name = get_u8_vec(caller, &memory, name_ptr, name_len)?;
+    let name = std::str::from_utf8(name)
+        .map_err(|_| WasmBoxError::Abi("Host function name is not valid UTF-8.".into()))?;
+    let function = caller.data().host_functions.get(name).ok_or_else(|| {
+        WasmBoxError::Abi(format!("Guest called unknown host function {}.", name))
+    })?;
+
+    let request = get_u8_vec(caller, &memory, request_ptr, request_len)?;
+    let response = function(request)?;
+    check_message_size(response.len(), max_message_bytes)?;
+    if response.is_empty() {
+        return Ok(0);
+    }
+
+    let len = u32::try_from(response.len()).map_err(|_| WasmBoxError::MessageTooLarge {
+        len: response.len(),
+        max: u32::MAX as usize,
+    })?;
+    let malloc = match caller.get_export(EXT_FN_MALLOC) {
+        Some(Extern::Func(malloc)) => malloc
+            .typed::<u32, u32, _>(&*caller)
+            .map_err(|_| WasmBoxError::MistypedExport(EXT_FN_MALLOC.into()))?,
+        _ => return Err(WasmBoxError::MissingExport(EXT_FN_MALLOC.into())),
+    };
+    let ptr = malloc.call(&mut *caller, len)?;
+    if ptr == 0 {
+        return Err(WasmBoxError::Abi(format!(
+            "Guest could not allocate {} bytes for a response.",
+            len
+        )));
+    }
+    memory
+        .write(&mut *caller, ptr as usize, &response)
+        .map_err(|_| {
+            WasmBoxError::Abi(format!(
+                "Guest allocated {} bytes at {}, which is out of bounds.",
+                len, ptr
+            ))
+        })?;
+
+    Ok((u64::from(ptr) << 32) | u64::from(len))
+}
@@ -0,0 +1,199 @@
+//! Rewrites guest modules so that state which the WebAssembly embedding API can't normally
+//! reach (non-exported globals and tables, and the functions which tables can refer to) is
+//! exported, and can therefore be snapshotted.
+
+use crate::WasmBoxError;
+use std::collections::BTreeSet;
+use wasm_encoder::{ExportKind, ExportSection, RawSection};
+use wasmparser::{
+    BinaryReader, BinaryReaderError, ConstExpr, ElementItem, ElementSectionReader,
+    ExportSectionReader, ExternalKind, GlobalSectionReader, ImportSectionReader, Operator,
+    TableSectionReader, TypeRef,
+};
+
+pub const GLOBAL_EXPORT_PREFIX: &str = "wasmbox_global_";
+pub const TABLE_EXPORT_PREFIX: &str = "wasmbox_table_";
+pub const FUNC_EXPORT_PREFIX: &str = "wasmbox_func_";
+
+const SECTION_CUSTOM: u8 = 0;
+const SECTION_IMPORT: u8 = 2;
+const SECTION_TABLE: u8 = 4;
+const SECTION_GLOBAL: u8 = 6;
+const SECTION_EXPORT: u8 = 7;
+const SECTION_ELEMENT: u8 = 9;
+
+const MAGIC: &[u8] = b"\0asm";
+/// Length of the magic number and version which begin every module.
+const HEADER_LEN: usize = 8;
+
+fn invalid(error: BinaryReaderError) -> WasmBoxError {
+    WasmBoxError::InvalidModule(error.to_string())
+}
+
+fn export_kind(kind: ExternalKind) -> ExportKind {
+    match kind {
+        ExternalKind::Func => ExportKind::Func,
+        ExternalKind::Table => ExportKind::Table,
+        ExternalKind::Memory => ExportKind::Memory,
+        ExternalKind::Global => ExportKind::Global,
+        ExternalKind::Tag => ExportKind::Tag,
+    }
+}
+
+/// Add the indices of the functions referred to by a constant expression to `funcs`.
+fn add_referenced_funcs(expr: &ConstExpr, funcs: &mut BTreeSet<u32>) -> Result<(), WasmBoxError> {
+    for operator in expr.get_operators_reader() {
+        if let Operator::RefFunc { function_index } = operator.map_err(invalid)? {
+            funcs.insert(function_index);
+        }
+    }
+
+    Ok(())
+}
+
+/// Add an export for every mutable global and every table defined by the module, named with
+/// `GLOBAL_EXPORT_PREFIX` or `TABLE_EXPORT_PREFIX` followed by its index. Also export every
+/// function which a table element can refer to, named with `FUNC_EXPORT_PREFIX` followed by
+/// its index. A module can only take a reference to a function which is exported or named
+/// outside of its code, in an element segment or a global's initializer, so these are the
+/// functions named there. Accepts and returns modules in binary format.
+pub fn export_state(wasm: &[u8]) -> Result<Vec<u8>, WasmBoxError> {
+    if wasm.len() < HEADER_LEN || !wasm.starts_with(MAGIC) {
+        return Err(WasmBoxError::InvalidModule(
+            "Not a WebAssembly module.".into(),
+        ));
+    }
+
+    let mut sections: Vec<(u8, &[u8], usize)> = Vec::new();
+    let mut reader = BinaryReader::new(&wasm[HEADER_LEN..]);
+    while !reader.eof() {
+        let id = reader.read_u8().map_err(invalid)?;
+        let len = reader.read_var_u32().map_err(invalid)? as usize;
+        let offset = HEADER_LEN + reader.original_position();
+        sections.push((id, reader.read_bytes(len).map_err(invalid)?, offset));
+    }
+
+    let mut imported_globals = 0;
+    let mut imported_tables = 0;
+    let mut mutable_globals = Vec::new();
+    let mut defined_tables = 0;
+    let mut funcs = BTreeSet::new();
+
+    for &(id, data, offset) in &sections {
+        match id {
+            SECTION_IMPORT => {
+                for import in ImportSectionReader::new(data, offset).map_err(invalid)? {
+                    match import.map_err(invalid)?.ty {
+                        TypeRef::Global(_) => imported_globals += 1,
+                        TypeRef::Table(_) => imported_tables += 1,
+                        _ => (),
+                    }
+                }
+            }
+            SECTION_GLOBAL => {
+                for (i, global) in GlobalSectionReader::new(data, offset)
+                    .map_err(invalid)?
+                    .into_iter()
+                    .enumerate()
+                {
+                    let global = global.map_err(invalid)?;
+                    if global.ty.mutable {
+                        mutable_globals.push(imported_globals + i as u32);
+                    }
+                    add_referenced_funcs(&global.init_expr, &mut funcs)?;
+                }
+            }
+            SECTION_TABLE => {
+                defined_tables = TableSectionReader::new(data, offset)
+                    .map_err(invalid)?
+                    .get_count();
+            }
+            SECTION_ELEMENT => {
+                for element in ElementSectionReader::new(data, offset).map_err(invalid)? {
+                    let items = element.map_err(invalid)?.items;
+                    for item in items.get_items_reader().map_err(invalid)? {
+                        match item.map_err(invalid)? {
+                            ElementItem::Func(index) => {
+                                funcs.insert(index);
+                            }
+                            ElementItem::Expr(expr) => add_referenced_funcs(&expr, &mut funcs)?,
+                        }
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    let mut existing = Vec::new();
+    for &(id, data, offset) in &sections {
+        if id == SECTION_EXPORT {
+            for export in ExportSectionReader::new(data, offset).map_err(invalid)? {
+                let export = export.map_err(invalid)?;
+                if export.kind == ExternalKind::Func {
+                    funcs.insert(export.index);
+                }
+                existing.push((export.name, export_kind(export.kind), export.index));
+            }
+        }
+    }
+
+    let mut exports = ExportSection::new();
+    for &(name, kind, index) in &existing {
+        exports.export(name, kind, index);
+    }
+    let added = mutable_globals
+        .into_iter()
+        .map(|index| (GLOBAL_EXPORT_PREFIX, ExportKind::Global, index))
+        .chain(
+            (imported_tables..imported_tables + defined_tables)
+                .map(|index| (TABLE_EXPORT_PREFIX, ExportKind::Table, index)),
+        )
+        .chain(
+            funcs
+                .into_iter()
+                .map(|index| (FUNC_EXPORT_PREFIX, ExportKind::Func, index)),
+        );
+    for (prefix, kind, index) in added {
+        let name = format!("{}{}", prefix, index);
+        match existing.iter().find(|(n, _, _)| *n == name) {
+            // The module has already been instrumented.
+            Some(&(_, k, i)) if k == kind && i == index => (),
+            Some(_) => return Err(WasmBoxError::ReservedExport(name)),
+            None => {
+                exports.export(&name, kind, index);
+            }
+        }
+    }
+
+    // Sections other than custom sections must appear in order of their id, so the export
+    // section goes right before the first section that should follow it.
+    let mut module = wasm_encoder::Module::new();
+    let mut exports_written = false;
+    for &(id, data, _) in &sections {
+        if id == SECTION_EXPORT {
+            continue;
+        }
+        if !exports_written && id != SECTION_CUSTOM && section_follows_exports(id) {
+            module.section(&exports);
+            exports_written = true;
+        }
+        module.section(&RawSection { id, data });
+    }
+    if !exports_written {
+        module.section(&exports);
+    }
+
+    Ok(module.finish())
+}
+
+/// Whether a (non-custom) section with the given id belongs after the export section.
+fn section_follows_exports(id: u8) -> bool {
+    // The data count section (12) and tag section (13) were added to the format later, so
+    // their ids are out of order: data count goes before code, and tag goes before globals.
+    match id {
+        12 => true,
+        13 => false,
+        id => id > SECTION_EXPORT,
+    }
+}
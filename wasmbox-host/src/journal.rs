@@ -0,0 +1,188 @@
+//! The journal format, which records every event that changes a box's state so that the box
+//! can be rebuilt by replaying them. Each event is followed by its outcome, once it has been
+//! applied.
+
+use crate::snapshot::{hex, ModuleHash};
+use crate::{Snapshot, WasmBoxError};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io::{BufRead, Write};
+
+/// Identifies a file as a WasmBox journal.
+const JOURNAL_MAGIC: [u8; 8] = *b"WBJOURNL";
+
+/// Version of the journal format. Increment whenever the layout of `JournalEvent` changes.
+const JOURNAL_FORMAT_VERSION: u32 = 5;
+
+/// Options for a journal started with `WasmBoxHost::set_journal_with_options`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JournalOptions {
+    /// Record the box's `state_hash` when journaling starts and after every event, so that
+    /// `verify_journal` can check replays against the recorded run. Hashing reads all of the
+    /// guest's memory, so this costs about as much as a snapshot per event.
+    pub state_hashes: bool,
+}
+
+/// What happened when an event was applied, recorded after it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct Outcome {
+    /// The guest missed its deadline while handling the event. Timing depends on the machine,
+    /// so replay reproduces the timeout instead of running the guest.
+    pub timed_out: bool,
+    /// The box's state hash after the event, if the journal records them.
+    pub state_hash: Option<[u8; 32]>,
+}
+
+/// An event as written to a journal. Borrows its contents, but is encoded identically to
+/// `JournalEvent`.
+#[derive(Serialize)]
+pub enum JournalEntry<'a, Input> {
+    Message(&'a Input),
+    SetTime(u64),
+    Restore(&'a Snapshot),
+    AdvanceTime(u64),
+    Respond { id: u32, response: &'a [u8] },
+    Outcome(Outcome),
+}
+
+/// An event as read from a journal.
+#[derive(Deserialize)]
+pub enum JournalEvent<Input> {
+    Message(Input),
+    SetTime(u64),
+    Restore(Box<Snapshot>),
+    AdvanceTime(u64),
+    Respond { id: u32, response: Vec<u8> },
+    Outcome(Outcome),
+}
+
+impl<Input> JournalEvent<Input> {
+    /// A short description of the event, for reports.
+    pub fn describe(&self) -> String {
+        match self {
+            JournalEvent::Message(_) => "message".into(),
+            JournalEvent::SetTime(time) => format!("set_time({})", time),
+            JournalEvent::Restore(_) => "restore_snapshot".into(),
+            JournalEvent::AdvanceTime(time) => format!("advance_time({})", time),
+            JournalEvent::Respond { id, .. } => format!("respond({})", id),
+            JournalEvent::Outcome(_) => "outcome".into(),
+        }
+    }
+}
+
+fn invalid(error: impl std::fmt::Display) -> WasmBoxError {
+    WasmBoxError::InvalidJournal(error.to_string())
+}
+
+/// Write the header of a journal, with the state hash of the box when journaling started if
+/// the journal records state hashes.
+pub fn write_header(
+    mut writer: impl Write,
+    module_hash: &ModuleHash,
+    state_hash: Option<[u8; 32]>,
+) -> Result<(), WasmBoxError> {
+    writer.write_all(&JOURNAL_MAGIC)?;
+    writer.write_all(&JOURNAL_FORMAT_VERSION.to_le_bytes())?;
+    writer.write_all(module_hash)?;
+    bincode::serialize_into(&mut writer, &state_hash)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Read the header of a journal, refusing it if it was recorded from a different module, and
+/// return the state hash recorded when journaling started, if any.
+pub fn read_header(
+    mut reader: impl BufRead,
+    module_hash: &ModuleHash,
+) -> Result<Option<[u8; 32]>, WasmBoxError> {
+    let mut magic = [0; JOURNAL_MAGIC.len()];
+    reader.read_exact(&mut magic).map_err(invalid)?;
+    if magic != JOURNAL_MAGIC {
+        return Err(invalid("Not a WasmBox journal."));
+    }
+
+    let mut version = [0; 4];
+    reader.read_exact(&mut version).map_err(invalid)?;
+    let version = u32::from_le_bytes(version);
+    if version != JOURNAL_FORMAT_VERSION {
+        return Err(invalid(format!(
+            "Journal has format version {}, but this host only reads version {}.",
+            version, JOURNAL_FORMAT_VERSION
+        )));
+    }
+
+    let mut journal_hash = ModuleHash::default();
+    reader.read_exact(&mut journal_hash).map_err(invalid)?;
+    if journal_hash != *module_hash {
+        return Err(invalid(format!(
+            "Journal was recorded from module {}, but the loaded module is {}.",
+            hex(&journal_hash),
+            hex(module_hash)
+        )));
+    }
+
+    bincode::deserialize_from(reader).map_err(invalid)
+}
+
+/// Append an event to a journal. The journal is flushed, so that the event is durable before
+/// it takes effect.
+pub fn write_entry<Input: Serialize>(
+    mut writer: impl Write,
+    entry: &JournalEntry<Input>,
+) -> Result<(), WasmBoxError> {
+    bincode::serialize_into(&mut writer, entry)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Read the next event from a journal, or `None` at the end of the journal.
+fn read_event<Input: DeserializeOwned>(
+    mut reader: impl BufRead,
+) -> Result<Option<JournalEvent<Input>>, WasmBoxError> {
+    if reader.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+
+    bincode::deserialize_from(reader).map(Some).map_err(invalid)
+}
+
+/// An event read from a journal, with its outcome if one was recorded.
+pub type RecordedEvent<Input> = (JournalEvent<Input>, Option<Outcome>);
+
+/// Reads the events of a journal, each with its outcome.
+pub struct JournalReader<R, Input> {
+    reader: R,
+    /// An event read while looking for the outcome of the one before it.
+    next: Option<JournalEvent<Input>>,
+}
+
+impl<R: BufRead, Input: DeserializeOwned> JournalReader<R, Input> {
+    pub fn new(reader: R) -> Self {
+        JournalReader { reader, next: None }
+    }
+
+    /// Read the next event and its outcome, or `None` at the end of the journal. The outcome
+    /// is `None` if the journal ends before it, because the recording host stopped while
+    /// applying the event.
+    pub fn next_event(&mut self) -> Result<Option<RecordedEvent<Input>>, WasmBoxError> {
+        let event = match self.next.take() {
+            Some(event) => event,
+            None => match read_event(&mut self.reader)? {
+                Some(event) => event,
+                None => return Ok(None),
+            },
+        };
+        if let JournalEvent::Outcome(_) = event {
+            return Err(invalid("Outcome without an event."));
+        }
+
+        match read_event(&mut self.reader)? {
+            Some(JournalEvent::Outcome(outcome)) => Ok(Some((event, Some(outcome)))),
+            next => {
+                self.next = next;
+                Ok(Some((event, None)))
+            }
+        }
+    }
+}
@@ -1,28 +1,79 @@
-use anyhow::anyhow;
-use serde::Deserialize;
+use journal::{JournalEntry, JournalEvent, JournalReader, Outcome};
+use module::{engine, instrument_module, COMPILED_MODULE_MAGIC, EPOCH_TICK};
 use serde::{de::DeserializeOwned, Serialize};
-use state::{WasmBoxState, WasmBoxStateSnapshot};
+use sha2::{Digest, Sha256};
+use snapshot::{module_hash, GlobalValue, TableElements};
+use state::WasmBoxState;
+use std::collections::{HashMap, VecDeque};
 use std::fs::File;
-use std::io::Write;
+use std::io::{BufReader, BufWriter, Write};
 use std::marker::PhantomData;
-use wasmtime::{Caller, Engine, Extern, Linker, Memory, Module, Store, TypedFunc};
-use wasmtime_wasi::WasiCtx;
+use std::time::Duration;
+use store::{GuestLimiter, StoreData};
+use wasmtime::{
+    Caller, Extern, Func, Global, Instance, Linker, Memory, Mutability, Store, Table, Trap,
+    TrapCode, TypedFunc, Val, ValType, WasmParams, WasmResults,
+};
 
+pub use error::WasmBoxError;
+pub use host_fn::HostFunctions;
+pub use journal::JournalOptions;
+pub use module::WasmBoxModule;
+pub use options::WasmBoxOptions;
+pub use request::HostRequest;
+pub use snapshot::{
+    Compression, DeltaSnapshot, Snapshot, SnapshotEncoding, SnapshotHeader, SnapshotOptions,
+    SnapshotStats, DELTA_PAGE_SIZE,
+};
+pub use state::derive_rng_seed;
+pub use verify::{verify_journal, Divergence, Verification};
+
+mod error;
+mod host_fn;
+mod instrument;
+mod journal;
+mod module;
+mod options;
+mod request;
+mod snapshot;
 mod state;
+mod store;
+mod verify;
 
 const ENV: &str = "env";
 const EXT_MEMORY: &str = "memory";
 const EXT_FN_CALLBACK: &str = "wasmbox_callback";
+const EXT_FN_EXIT: &str = "wasmbox_exit";
+const EXT_FN_CALL_HOST: &str = "wasmbox_call_host";
+const EXT_FN_REQUEST: &str = "wasmbox_request";
+const EXT_FN_CANCEL_REQUEST: &str = "wasmbox_cancel_request";
+const EXT_FN_RESPOND: &str = "wasmbox_respond";
+const EXT_FN_TIMER: &str = "wasmbox_timer";
+const EXT_FN_CANCEL_TIMER: &str = "wasmbox_cancel_timer";
+const EXT_FN_WAKE: &str = "wasmbox_wake";
 const EXT_FN_SEND: &str = "wasmbox_send";
 const EXT_FN_MALLOC: &str = "wasmbox_malloc";
 const EXT_FN_FREE: &str = "wasmbox_free";
 const EXT_FN_INITIALIZE: &str = "wasmbox_initialize";
 
+const WASM_PAGE_SIZE: usize = 0x10000;
+
+/// Fuel given to calls without a limit. Wasmtime tracks fuel as an `i64` which also counts
+/// all fuel ever added, so this is large enough never to run out in practice but leaves
+/// headroom below `i64::MAX`.
+const UNLIMITED_FUEL: u64 = 1 << 48;
+
+/// Epoch deadline given to calls without a deadline, in ticks. Far enough in the future never
+/// to be reached, but small enough not to overflow when added to the current epoch.
+const UNLIMITED_EPOCH_TICKS: u64 = 1 << 48;
+
 #[inline]
-fn get_memory<T>(caller: &mut Caller<'_, T>) -> Memory {
+fn get_memory<T>(caller: &mut Caller<'_, T>) -> Result<Memory, WasmBoxError> {
     match caller.get_export(EXT_MEMORY) {
-        Some(Extern::Memory(mem)) => mem,
-        _ => panic!(),
+        Some(Extern::Memory(mem)) => Ok(mem),
+        _ => Err(WasmBoxError::Abi(
+            "Guest does not export its memory.".into(),
+        )),
     }
 }
 
@@ -32,14 +83,16 @@ fn get_u8_vec<'a, T>(
     memory: &'a Memory,
     start: u32,
     len: u32,
-) -> &'a [u8] {
-    let data = memory
-        .data(caller)
-        .get(start as usize..(start + len) as usize);
-    match data {
-        Some(data) => data,
-        None => panic!(),
-    }
+) -> Result<&'a [u8], WasmBoxError> {
+    let data = (start as usize)
+        .checked_add(len as usize)
+        .and_then(|end| memory.data(caller).get(start as usize..end));
+    data.ok_or_else(|| {
+        WasmBoxError::Abi(format!(
+            "Guest passed a message of {} bytes at {}, which is out of bounds.",
+            len, start
+        ))
+    })
 }
 
 #[inline]
@@ -48,173 +101,1384 @@ fn get_deserialize<'a, T, R>(
     memory: &'a Memory,
     start: u32,
     len: u32,
-) -> anyhow::Result<R>
+) -> Result<R, WasmBoxError>
 where
     R: DeserializeOwned,
 {
-    let data = get_u8_vec(caller, memory, start, len);
+    let data = get_u8_vec(caller, memory, start, len)?;
     Ok(bincode::deserialize(data)?)
 }
 
-pub fn prepare_module(input_path: &str, output_path: &str) -> anyhow::Result<()> {
-    let input_module = std::fs::read(input_path)?;
-    let engine = Engine::default();
+/// Look up a function the wasmbox ABI requires the guest to export.
+fn get_typed_func<Params, Results>(
+    instance: &Instance,
+    store: &mut Store<impl Sized>,
+    name: &str,
+) -> Result<TypedFunc<Params, Results>, WasmBoxError>
+where
+    Params: WasmParams,
+    Results: WasmResults,
+{
+    match instance.get_func(&mut *store, name) {
+        Some(func) => func
+            .typed(&*store)
+            .map_err(|_| WasmBoxError::MistypedExport(name.into())),
+        None => Err(WasmBoxError::MissingExport(name.into())),
+    }
+}
+
+/// Look up a function which guests may optionally export.
+fn get_optional_typed_func<Params, Results>(
+    instance: &Instance,
+    store: &mut Store<impl Sized>,
+    name: &str,
+) -> Result<Option<TypedFunc<Params, Results>>, WasmBoxError>
+where
+    Params: WasmParams,
+    Results: WasmResults,
+{
+    match get_typed_func(instance, store, name) {
+        Ok(func) => Ok(Some(func)),
+        Err(WasmBoxError::MissingExport(_)) => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+/// Replace the fuel remaining in the store with `fuel`, or with an effectively unlimited
+/// amount if `None`.
+fn set_store_fuel<T>(store: &mut Store<T>, fuel: Option<u64>) -> Result<(), WasmBoxError> {
+    // Consuming zero fuel fails if the store is already out of fuel.
+    let remaining = store.consume_fuel(0).unwrap_or(0);
+    let fuel = fuel.unwrap_or(UNLIMITED_FUEL);
+
+    if fuel > remaining {
+        store
+            .add_fuel(fuel - remaining)
+            .map_err(WasmBoxError::Runtime)?;
+    } else if fuel < remaining {
+        // The store can't be drained entirely, so a limit of zero leaves one unit.
+        store
+            .consume_fuel(remaining - fuel.max(1))
+            .map_err(WasmBoxError::Runtime)?;
+    }
+
+    Ok(())
+}
+
+/// Interrupt the guest once `deadline` has passed, or effectively never if `None`.
+fn set_store_deadline<T>(store: &mut Store<T>, deadline: Option<Duration>) {
+    let ticks = match deadline {
+        // The current tick is already partly over, so allow one more.
+        Some(deadline) => (deadline.as_nanos() / EPOCH_TICK.as_nanos()) as u64 + 1,
+        None => UNLIMITED_EPOCH_TICKS,
+    };
+    store.set_epoch_deadline(ticks);
+}
+
+/// Make the guest trap because a host function failed, recording the error so that it can be
+/// reported instead of the trap.
+fn host_trap<Output>(caller: &mut Caller<'_, StoreData<Output>>, error: WasmBoxError) -> Trap {
+    match error {
+        // A trap from calling back into the guest is passed through.
+        WasmBoxError::Trap(trap) => trap,
+        error => {
+            let trap = Trap::new(error.to_string());
+            caller.data_mut().host_error = Some(error);
+            trap
+        }
+    }
+}
+
+/// Check the size of a message to or from the guest against the limit, if any.
+fn check_message_size(len: usize, max: Option<usize>) -> Result<(), WasmBoxError> {
+    match max {
+        Some(max) if len > max => Err(WasmBoxError::MessageTooLarge { len, max }),
+        _ => Ok(()),
+    }
+}
+
+pub fn prepare_module(input_path: &str, output_path: &str) -> Result<(), WasmBoxError> {
+    let input_bytes = std::fs::read(input_path)?;
+    let input_module = instrument_module(&input_bytes)?;
+    let engine = engine()?;
 
-    let result = engine.precompile_module(&input_module)?;
-    std::fs::write(output_path, &result)?;
+    let result = engine
+        .precompile_module(&input_module)
+        .map_err(WasmBoxError::Module)?;
+
+    let mut output = BufWriter::new(File::create(output_path)?);
+    output.write_all(&COMPILED_MODULE_MAGIC)?;
+    output.write_all(&module_hash(&input_bytes))?;
+    output.write_all(&result)?;
+    output.flush()?;
 
     Ok(())
 }
 
+/// Whether a box with `options` can pass the guest's output to its callback as soon as the
+/// guest sends it. Output from a message which might be rolled back is held until the guest
+/// returns, so that it can be discarded.
+fn streams(options: &WasmBoxOptions) -> bool {
+    !options.transactional && options.message_fuel.is_none() && options.message_deadline.is_none()
+}
+
+/// The raw pointer of a function, which identifies it within its store.
+fn raw_func<T>(store: &Store<T>, func: &Func) -> usize {
+    // The pointer is only compared, never turned back into a function.
+    unsafe { func.to_raw(store) }
+}
+
+/// Whether a guest trap was caused by its epoch deadline passing.
+fn is_interrupt(trap: &Trap) -> bool {
+    matches!(trap.trap_code(), Some(TrapCode::Interrupt))
+}
+
 pub struct WasmBoxHost<Input: Serialize, Output: DeserializeOwned> {
-    store: Store<WasiCtx>,
+    store: Store<StoreData<Output>>,
     memory: Memory,
+    globals: Vec<(String, Global)>,
+    tables: Vec<(String, Table)>,
+    /// The functions which table elements can refer to, by function index, and their indices
+    /// by raw pointer, so that table elements can be snapshotted by index.
+    funcs: HashMap<u32, Func>,
+    func_indices: HashMap<usize, u32>,
     state: WasmBoxState,
+    module: WasmBoxModule,
+    /// Output queued for a host without a callback.
+    queue: VecDeque<Output>,
+    options: WasmBoxOptions,
+    last_fuel_consumed: u64,
+    poisoned: bool,
+    /// Copy of the box's state to roll back to if a message fails, when the options call for
+    /// one. Brought up to date before each message by copying only the pages that changed.
+    checkpoint: Option<Snapshot>,
+    /// If set, every event which changes the box's state is appended here before it is
+    /// applied, and its outcome after.
+    journal: Option<Box<dyn Write + Send>>,
+    journal_options: JournalOptions,
 
     fn_malloc: TypedFunc<u32, u32>,
     fn_free: TypedFunc<(u32, u32), ()>,
     fn_send: TypedFunc<(u32, u32), ()>,
+    fn_initialize: TypedFunc<(), ()>,
+    fn_respond: Option<TypedFunc<(u32, u32, u32), ()>>,
+    fn_wake: Option<TypedFunc<(), ()>>,
 
     _ph_i: PhantomData<Input>,
-    _ph_o: PhantomData<Output>,
 }
 
 impl<Input: Serialize, Output: DeserializeOwned> WasmBoxHost<Input, Output> {
-    fn put_data(&mut self, data: &[u8]) -> anyhow::Result<(u32, u32)> {
-        #[allow(clippy::cast_possible_truncation)]
-        let len = data.len() as u32;
+    fn put_data(&mut self, data: &[u8]) -> Result<(u32, u32), WasmBoxError> {
+        let len = u32::try_from(data.len()).map_err(|_| WasmBoxError::MessageTooLarge {
+            len: data.len(),
+            max: u32::MAX as usize,
+        })?;
         let pt = self.fn_malloc.call(&mut self.store, len)?;
+        if pt == 0 {
+            return Err(WasmBoxError::Abi(format!(
+                "Guest could not allocate {} bytes for a message.",
+                len
+            )));
+        }
 
-        self.memory.write(&mut self.store, pt as usize, data)?;
+        self.memory
+            .write(&mut self.store, pt as usize, data)
+            .map_err(|_| {
+                WasmBoxError::Abi(format!(
+                    "Guest allocated {} bytes at {}, which is out of bounds.",
+                    len, pt
+                ))
+            })?;
 
         Ok((pt, len))
     }
 
-    fn try_send(&mut self, message: &Input) -> anyhow::Result<()> {
-        let (pt, len) = self.put_data(&bincode::serialize(message)?)?;
+    fn set_fuel(&mut self, fuel: Option<u64>) -> Result<(), WasmBoxError> {
+        set_store_fuel(&mut self.store, fuel)
+    }
+
+    fn is_out_of_fuel(&mut self) -> bool {
+        self.store.consume_fuel(0).is_err()
+    }
+
+    fn set_deadline(&mut self, deadline: Option<Duration>) {
+        set_store_deadline(&mut self.store, deadline);
+    }
+
+    /// Run a call into the guest with the given fuel limit and deadline, recording the fuel it
+    /// consumed.
+    fn metered<R>(
+        &mut self,
+        fuel: Option<u64>,
+        deadline: Option<Duration>,
+        call: impl FnOnce(&mut Self) -> Result<R, WasmBoxError>,
+    ) -> Result<R, WasmBoxError> {
+        self.set_fuel(fuel)?;
+        self.set_deadline(deadline);
+        self.store.data_mut().limiter.reset();
+        self.store.data_mut().host_error = None;
+        let fuel_before = self.store.fuel_consumed().unwrap_or_default();
 
-        self.fn_send.call(&mut self.store, (pt, len))?;
+        let result = call(self);
+
+        let fuel_after = self.store.fuel_consumed().unwrap_or_default();
+        self.last_fuel_consumed = fuel_after.saturating_sub(fuel_before);
+
+        let error = match result {
+            Ok(result) => return Ok(result),
+            Err(error) => error,
+        };
+
+        if self.is_out_of_fuel() {
+            return Err(WasmBoxError::OutOfFuel);
+        }
+        if let WasmBoxError::Trap(trap) = &error {
+            if is_interrupt(trap) {
+                return Err(WasmBoxError::Timeout);
+            }
+        }
+        if let Some(host_error) = self.store.data_mut().host_error.take() {
+            return Err(host_error);
+        }
+        // A guest denied memory will usually abort, so report the denial as the cause. This
+        // can only be a guess, so a guest which grows successfully after being denied is
+        // assumed to have recovered.
+        if let WasmBoxError::Trap(_) = &error {
+            let limiter = &self.store.data().limiter;
+            if limiter.memory_denied {
+                return Err(WasmBoxError::MemoryLimitExceeded);
+            }
+            if limiter.table_denied {
+                return Err(WasmBoxError::TableLimitExceeded);
+            }
+        }
+
+        Err(error)
+    }
+
+    /// Copy `message` into the guest and pass it to `send`, which calls into the guest.
+    fn try_send(
+        &mut self,
+        message: &[u8],
+        send: impl FnOnce(&mut Self, u32, u32) -> Result<(), Trap>,
+    ) -> Result<(), WasmBoxError> {
+        let (pt, len) = self.put_data(message)?;
+
+        send(self, pt, len)?;
 
         self.fn_free.call(&mut self.store, (pt, len))?;
         Ok(())
     }
 
-    pub fn from_compiled_module<F>(module_file: &str, callback: F) -> anyhow::Result<Self>
+    pub fn from_compiled_module<F>(module_file: &str, callback: F) -> Result<Self, WasmBoxError>
     where
         F: Fn(Output) + 'static + Send + Sync,
         Self: Sized,
     {
-        let engine = Engine::default();
-        let module = unsafe { Module::deserialize_file(&engine, module_file)? };
+        Self::new(&WasmBoxModule::from_compiled_module(module_file)?, callback)
+    }
 
-        Ok(Self::init(engine, module, callback)?)
+    pub fn from_wasm_file<F>(module_file: &str, callback: F) -> Result<Self, WasmBoxError>
+    where
+        F: Fn(Output) + 'static + Send + Sync,
+        Self: Sized,
+    {
+        Self::new(&WasmBoxModule::from_wasm_file(module_file)?, callback)
     }
 
-    pub fn from_wasm_file<F>(module_file: &str, callback: F) -> anyhow::Result<Self>
+    /// Instantiate a module and run the guest's initialization.
+    pub fn new<F>(module: &WasmBoxModule, callback: F) -> Result<Self, WasmBoxError>
     where
         F: Fn(Output) + 'static + Send + Sync,
         Self: Sized,
     {
-        let engine = Engine::default();
-        let module = Module::from_file(&engine, module_file)?;
+        Self::new_with_options(module, WasmBoxOptions::default(), callback)
+    }
 
-        Ok(Self::init(engine, module, callback)?)
+    pub fn new_with_options<F>(
+        module: &WasmBoxModule,
+        options: WasmBoxOptions,
+        callback: F,
+    ) -> Result<Self, WasmBoxError>
+    where
+        F: Fn(Output) + 'static + Send + Sync,
+        Self: Sized,
+    {
+        let mut host = Self::instantiate(module, options, Some(Box::new(callback)))?;
+        host.initialize()?;
+
+        Ok(host)
+    }
+
+    /// Instantiate a module and run the guest's initialization. Instead of being passed to a
+    /// callback, the guest's output is queued in the host, to be taken with `drain_outputs`
+    /// or `next_output`.
+    pub fn new_with_output_queue(
+        module: &WasmBoxModule,
+        options: WasmBoxOptions,
+    ) -> Result<Self, WasmBoxError> {
+        let mut host = Self::instantiate(module, options, None)?;
+        host.initialize()?;
+
+        Ok(host)
+    }
+
+    fn initialize(&mut self) -> Result<(), WasmBoxError> {
+        let (init_fuel, init_deadline) = (self.options.init_fuel, self.options.init_deadline);
+        let result = self.metered(init_fuel, init_deadline, |host| {
+            Ok(host.fn_initialize.call(&mut host.store, ())?)
+        });
+        self.collect_pending();
+        self.dispatch_outbox();
+
+        result
+    }
+
+    /// Instantiate a module directly into the state captured by a snapshot.
+    ///
+    /// The guest's initialization is not run, so unlike constructing a host and then
+    /// restoring a snapshot into it, this has no side effects such as calls to `callback`.
+    pub fn from_snapshot<F>(
+        module: &WasmBoxModule,
+        snapshot: &Snapshot,
+        callback: F,
+    ) -> Result<Self, WasmBoxError>
+    where
+        F: Fn(Output) + 'static + Send + Sync,
+        Self: Sized,
+    {
+        Self::from_snapshot_with_options(module, snapshot, WasmBoxOptions::default(), callback)
     }
 
-    fn init<F>(engine: Engine, module: Module, callback: F) -> anyhow::Result<Self>
+    pub fn from_snapshot_with_options<F>(
+        module: &WasmBoxModule,
+        snapshot: &Snapshot,
+        options: WasmBoxOptions,
+        callback: F,
+    ) -> Result<Self, WasmBoxError>
     where
         F: Fn(Output) + 'static + Send + Sync,
         Self: Sized,
     {
-        let state = WasmBoxState::new();
-        let wasi = state.wasi_ctx();
+        let mut host = Self::instantiate(module, options, Some(Box::new(callback)))?;
+        host.load_snapshot(snapshot)?;
 
-        let mut store = Store::new(&engine, wasi);
-        let mut linker = Linker::new(&engine);
-        wasmtime_wasi::add_to_linker(&mut linker, |s| s)?;
+        Ok(host)
+    }
+
+    /// Instantiate a module directly into the state captured by a snapshot, queueing the
+    /// guest's output in the host as with `new_with_output_queue`.
+    pub fn from_snapshot_with_output_queue(
+        module: &WasmBoxModule,
+        snapshot: &Snapshot,
+        options: WasmBoxOptions,
+    ) -> Result<Self, WasmBoxError> {
+        let mut host = Self::instantiate(module, options, None)?;
+        host.load_snapshot(snapshot)?;
+
+        Ok(host)
+    }
+
+    fn instantiate(
+        module: &WasmBoxModule,
+        options: WasmBoxOptions,
+        callback: Option<Box<dyn Fn(Output) + Send + Sync>>,
+    ) -> Result<Self, WasmBoxError> {
+        let state = WasmBoxState::new(options.rng_seed);
+        let limiter = GuestLimiter {
+            max_memory_bytes: options.max_memory_bytes,
+            max_table_elements: options.max_table_elements,
+            ..GuestLimiter::default()
+        };
+        let max_message_bytes = options.max_message_bytes;
+        let data = StoreData {
+            wasi: state.wasi_ctx(),
+            callback,
+            stream: streams(&options),
+            outbox: Vec::new(),
+            limiter,
+            requests: Vec::new(),
+            outstanding_request_ids: Vec::new(),
+            cancelled_requests: Vec::new(),
+            timers: Vec::new(),
+            cancelled_timers: Vec::new(),
+            exit_value: None,
+            host_error: None,
+            host_functions: options.host_functions.clone(),
+        };
+
+        let mut store = Store::new(&module.engine, data);
+        store.limiter(|data| &mut data.limiter);
+        let mut linker = Linker::new(&module.engine);
+        wasmtime_wasi::add_to_linker(&mut linker, |data: &mut StoreData<Output>| &mut data.wasi)
+            .map_err(WasmBoxError::Runtime)?;
 
         {
-            linker.func_wrap(
-                ENV,
-                EXT_FN_CALLBACK,
-                move |mut caller: Caller<'_, WasiCtx>, start: u32, len: u32| {
-                    let memory = get_memory(&mut caller);
-                    let message: Output = get_deserialize(&caller, &memory, start, len)?;
+            linker
+                .func_wrap(
+                    ENV,
+                    EXT_FN_CALLBACK,
+                    move |mut caller: Caller<'_, StoreData<Output>>, start: u32, len: u32| {
+                        let message: Result<Output, _> =
+                            check_message_size(len as usize, max_message_bytes)
+                                .and_then(|()| get_memory(&mut caller))
+                                .and_then(|memory| get_deserialize(&caller, &memory, start, len));
+
+                        match message {
+                            Ok(message) => {
+                                let data = caller.data_mut();
+                                match &data.callback {
+                                    Some(callback) if data.stream => callback(message),
+                                    _ => data.outbox.push(message),
+                                }
+                                Ok(())
+                            }
+                            Err(error) => Err(host_trap(&mut caller, error)),
+                        }
+                    },
+                )
+                .map_err(WasmBoxError::Runtime)?;
+
+            linker
+                .func_wrap(
+                    ENV,
+                    EXT_FN_EXIT,
+                    move |mut caller: Caller<'_, StoreData<Output>>, start: u32, len: u32| {
+                        let exit_value = check_message_size(len as usize, max_message_bytes)
+                            .and_then(|()| get_memory(&mut caller))
+                            .and_then(|memory| {
+                                get_u8_vec(&caller, &memory, start, len).map(<[u8]>::to_vec)
+                            });
+
+                        match exit_value {
+                            Ok(exit_value) => {
+                                caller.data_mut().exit_value = Some(exit_value);
+                                Ok(())
+                            }
+                            Err(error) => Err(host_trap(&mut caller, error)),
+                        }
+                    },
+                )
+                .map_err(WasmBoxError::Runtime)?;
 
-                    callback(message);
-                    Ok(())
-                },
-            )?;
+            linker
+                .func_wrap(
+                    ENV,
+                    EXT_FN_CALL_HOST,
+                    move |mut caller: Caller<'_, StoreData<Output>>,
+                          name_ptr: u32,
+                          name_len: u32,
+                          request_ptr: u32,
+                          request_len: u32| {
+                        host_fn::call_host(
+                            &mut caller,
+                            (name_ptr, name_len),
+                            (request_ptr, request_len),
+                            max_message_bytes,
+                        )
+                        .map_err(|error| host_trap(&mut caller, error))
+                    },
+                )
+                .map_err(WasmBoxError::Runtime)?;
+
+            linker
+                .func_wrap(
+                    ENV,
+                    EXT_FN_REQUEST,
+                    move |mut caller: Caller<'_, StoreData<Output>>,
+                          id: u32,
+                          ptr: u32,
+                          len: u32| {
+                        request::receive_request(&mut caller, id, (ptr, len), max_message_bytes)
+                            .map_err(|error| host_trap(&mut caller, error))
+                    },
+                )
+                .map_err(WasmBoxError::Runtime)?;
+
+            linker
+                .func_wrap(
+                    ENV,
+                    EXT_FN_CANCEL_REQUEST,
+                    |mut caller: Caller<'_, StoreData<Output>>, id: u32| {
+                        caller.data_mut().cancelled_requests.push(id);
+                    },
+                )
+                .map_err(WasmBoxError::Runtime)?;
+
+            linker
+                .func_wrap(
+                    ENV,
+                    EXT_FN_TIMER,
+                    |mut caller: Caller<'_, StoreData<Output>>, deadline: u64| {
+                        caller.data_mut().timers.push(deadline);
+                    },
+                )
+                .map_err(WasmBoxError::Runtime)?;
+
+            linker
+                .func_wrap(
+                    ENV,
+                    EXT_FN_CANCEL_TIMER,
+                    |mut caller: Caller<'_, StoreData<Output>>, deadline: u64| {
+                        caller.data_mut().cancelled_timers.push(deadline);
+                    },
+                )
+                .map_err(WasmBoxError::Runtime)?;
         }
 
-        let instance = linker.instantiate(&mut store, &module)?;
+        // Instantiating runs the module's start function, if it has one, so it is metered
+        // like initialization.
+        set_store_fuel(&mut store, options.init_fuel)?;
+        set_store_deadline(&mut store, options.init_deadline);
+        let instance = match linker.instantiate(&mut store, &module.module) {
+            Ok(instance) => instance,
+            Err(_) if store.consume_fuel(0).is_err() => return Err(WasmBoxError::OutOfFuel),
+            Err(error) if error.downcast_ref().is_some_and(is_interrupt) => {
+                return Err(WasmBoxError::Timeout)
+            }
+            Err(error) => return Err(WasmBoxError::Module(error)),
+        };
 
-        let memory = instance
-            .get_memory(&mut store, EXT_MEMORY)
-            .ok_or_else(|| anyhow!("Couldn't allocate memory."))?;
+        let memory = match instance.get_export(&mut store, EXT_MEMORY) {
+            Some(Extern::Memory(memory)) => memory,
+            Some(_) => return Err(WasmBoxError::MistypedExport(EXT_MEMORY.into())),
+            None => return Err(WasmBoxError::MissingExport(EXT_MEMORY.into())),
+        };
 
-        let fn_malloc = instance.get_typed_func::<u32, u32, _>(&mut store, EXT_FN_MALLOC)?;
-        let fn_free = instance.get_typed_func::<(u32, u32), (), _>(&mut store, EXT_FN_FREE)?;
-        let fn_send = instance.get_typed_func::<(u32, u32), (), _>(&mut store, EXT_FN_SEND)?;
-        let fn_initialize = instance.get_typed_func::<(), (), _>(&mut store, EXT_FN_INITIALIZE)?;
+        let fn_malloc = get_typed_func::<u32, u32>(&instance, &mut store, EXT_FN_MALLOC)?;
+        let fn_free = get_typed_func::<(u32, u32), ()>(&instance, &mut store, EXT_FN_FREE)?;
+        let fn_send = get_typed_func::<(u32, u32), ()>(&instance, &mut store, EXT_FN_SEND)?;
+        let fn_initialize = get_typed_func::<(), ()>(&instance, &mut store, EXT_FN_INITIALIZE)?;
+        let fn_respond =
+            get_optional_typed_func::<(u32, u32, u32), ()>(&instance, &mut store, EXT_FN_RESPOND)?;
+        let fn_wake = get_optional_typed_func::<(), ()>(&instance, &mut store, EXT_FN_WAKE)?;
 
-        fn_initialize.call(&mut store, ())?;
+        let mut globals = Vec::new();
+        let mut tables = Vec::new();
+        let mut funcs = HashMap::new();
+        for export in instance.exports(&mut store) {
+            let name = export.name().to_string();
+            match export.into_extern() {
+                Extern::Global(global) => globals.push((name, global)),
+                Extern::Table(table) => tables.push((name, table)),
+                Extern::Func(func) => {
+                    let index = name.strip_prefix(instrument::FUNC_EXPORT_PREFIX);
+                    if let Some(Ok(index)) = index.map(str::parse) {
+                        funcs.insert(index, func);
+                    }
+                }
+                _ => (),
+            }
+        }
+        globals.retain(|(_, global)| global.ty(&store).mutability() == Mutability::Var);
+        let func_indices = funcs
+            .iter()
+            .map(|(&index, func)| (raw_func(&store, func), index))
+            .collect();
 
         Ok(WasmBoxHost {
             store,
             memory,
+            globals,
+            tables,
+            funcs,
+            func_indices,
             state,
+            module: module.clone(),
+            queue: VecDeque::new(),
+            options,
+            last_fuel_consumed: 0,
+            poisoned: false,
+            checkpoint: None,
+            journal: None,
+            journal_options: JournalOptions::default(),
             fn_malloc,
             fn_free,
             fn_send,
-            _ph_i: PhantomData::default(),
-            _ph_o: PhantomData::default(),
+            fn_initialize,
+            fn_respond,
+            fn_wake,
+            _ph_i: PhantomData,
         })
     }
 
-    pub fn set_time(&mut self, time: u64) {
-        self.state.set_time(time)
+    pub fn set_time(&mut self, time: u64) -> Result<(), WasmBoxError> {
+        self.record(&JournalEntry::SetTime(time))?;
+        self.state.set_time(time);
+
+        self.record_outcome(Ok(()))
     }
 
-    pub fn message(&mut self, input: &Input) {
-        self.try_send(input).expect("Error sending message.")
+    /// Set the time, as with `set_time`, and wake the guest if any timers it has set with
+    /// `WasmBoxContext::sleep` or `sleep_until` are due. Waking the guest is like delivering a
+    /// message: output it sends is passed to the callback, and it is subject to the same limits
+    /// and rollback.
+    pub fn advance_time(&mut self, time: u64) -> Result<(), WasmBoxError> {
+        self.record(&JournalEntry::AdvanceTime(time))?;
+        let result = self.wake_due_timers(time);
+        self.record_outcome(result)
     }
 
-    pub fn snapshot_state(&self) -> anyhow::Result<Snapshot> {
-        let memory = self.memory.data(&self.store);
+    fn wake_due_timers(&mut self, time: u64) -> Result<(), WasmBoxError> {
+        if !self.state.has_due_timer(time) {
+            self.state.set_time(time);
+            return Ok(());
+        }
+
+        let fn_wake = self
+            .fn_wake
+            .ok_or_else(|| WasmBoxError::MissingExport(EXT_FN_WAKE.into()))?;
+        self.state.set_time(time);
+        let result = self.run_guest(|host| Ok(fn_wake.call(&mut host.store, ())?));
+        if result.is_ok() {
+            // Guests which don't cancel the timers which fired have still been woken for them.
+            self.state.remove_due_timers(time);
+        }
+        self.dispatch_outbox();
+
+        result
+    }
+
+    /// The earliest time at which a timer set by the guest is due, if it has set any. Call
+    /// `advance_time` at that time to wake the guest. Guests built with the `wasmbox` crate
+    /// cancel timers they no longer wait for, including those which fire while they handle a
+    /// message after `set_time`, so those are not counted.
+    pub fn next_timer_deadline(&self) -> Option<u64> {
+        self.state.next_timer_deadline()
+    }
+
+    /// Start appending every event which changes the box's state to `journal`: messages
+    /// (including those sent with `call`), responses, changes to the time (including with
+    /// `advance_time`), and restored snapshots. Each event is written and flushed before it
+    /// is applied, and followed by its outcome once it has been applied. A box can be rebuilt
+    /// by restoring the snapshot it was in when journaling started, and then passing the
+    /// journal to `replay_journal`.
+    ///
+    /// Replay reproduces the box's state only if the functions registered with
+    /// `register_host_fn` or `WasmBoxOptions::host_functions` return the same responses when
+    /// replayed as they did when the journal was recorded.
+    pub fn set_journal(
+        &mut self,
+        journal: impl Write + Send + 'static,
+    ) -> Result<(), WasmBoxError> {
+        self.set_journal_with_options(journal, JournalOptions::default())
+    }
+
+    /// Start journaling, as with `set_journal`, with the given options.
+    pub fn set_journal_with_options(
+        &mut self,
+        journal: impl Write + Send + 'static,
+        options: JournalOptions,
+    ) -> Result<(), WasmBoxError> {
+        let state_hash = match options.state_hashes {
+            true => Some(self.state_hash()?),
+            false => None,
+        };
+        let mut journal = Box::new(journal);
+        journal::write_header(&mut journal, &self.module.hash, state_hash)?;
+        self.journal = Some(journal);
+        self.journal_options = options;
+
+        Ok(())
+    }
+
+    /// Stop journaling, returning the journal.
+    pub fn take_journal(&mut self) -> Option<Box<dyn Write + Send>> {
+        self.journal.take()
+    }
+
+    fn record(&mut self, entry: &JournalEntry<Input>) -> Result<(), WasmBoxError> {
+        match &mut self.journal {
+            Some(journal) => journal::write_entry(journal, entry),
+            None => Ok(()),
+        }
+    }
+
+    /// Record the outcome of the event recorded last, and pass on its result.
+    fn record_outcome(&mut self, result: Result<(), WasmBoxError>) -> Result<(), WasmBoxError> {
+        if self.journal.is_none() {
+            return result;
+        }
+
+        let state_hash = match self.journal_options.state_hashes {
+            true => match self.state_hash() {
+                Ok(state_hash) => Some(state_hash),
+                Err(error) => return result.and(Err(error)),
+            },
+            false => None,
+        };
+        let outcome = Outcome {
+            timed_out: matches!(result, Err(WasmBoxError::Timeout)),
+            state_hash,
+        };
+        let recorded = self.record(&JournalEntry::Outcome(outcome));
+
+        result.and(recorded)
+    }
+
+    /// The seed of the guest's random number generator. This is part of the box's state, so
+    /// after restoring a snapshot it is the seed of the box the snapshot was taken from.
+    pub fn rng_seed(&self) -> [u8; 32] {
+        self.state.rng_seed()
+    }
+
+    /// Register a function which the guest can call by `name` with `call_host`. To make a
+    /// function available while the guest initializes, register it in
+    /// `WasmBoxOptions::host_functions` instead.
+    pub fn register_host_fn<Request, Response, F>(&mut self, name: &str, function: F)
+    where
+        Request: DeserializeOwned,
+        Response: Serialize,
+        F: Fn(Request) -> Response + 'static + Send + Sync,
+    {
+        self.options.host_functions.register(name, function);
+        self.store.data_mut().host_functions = self.options.host_functions.clone();
+    }
+
+    /// Set the maximum fuel the guest may consume while handling each message, or `None`
+    /// for no limit.
+    pub fn set_message_fuel(&mut self, fuel: Option<u64>) {
+        self.options.message_fuel = fuel;
+        self.store.data_mut().stream = streams(&self.options);
+    }
 
+    /// Fuel consumed by the guest during the most recent call to `message` (or during
+    /// initialization, if no message has been sent yet).
+    pub fn last_fuel_consumed(&self) -> u64 {
+        self.last_fuel_consumed
+    }
+
+    /// Set the maximum wall-clock time the guest may spend handling each message, or `None`
+    /// for no limit.
+    pub fn set_message_deadline(&mut self, deadline: Option<Duration>) {
+        self.options.message_deadline = deadline;
+        self.store.data_mut().stream = streams(&self.options);
+    }
+
+    /// Whether the guest has finished, by returning from its `run` function. A finished box
+    /// refuses messages with `WasmBoxError::BoxFinished`.
+    pub fn is_finished(&self) -> bool {
+        self.state.exit_value().is_some()
+    }
+
+    /// The value the guest's `run` function returned, or `None` if it hasn't finished. `T`
+    /// must match the return type of `run`, which is `()` if it doesn't declare one.
+    pub fn exit_value<T: DeserializeOwned>(&self) -> Result<Option<T>, WasmBoxError> {
+        match self.state.exit_value() {
+            Some(exit_value) => Ok(Some(bincode::deserialize(exit_value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether an earlier message failed in a way that left the guest in an unknown state.
+    /// A poisoned box refuses messages until a snapshot is restored into it.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    /// Deliver a message to the guest.
+    ///
+    /// Output the guest sends while handling the message is passed to the callback as it is
+    /// sent, or once the guest returns if the message might be rolled back. If the guest
+    /// fails, the box is poisoned (see `is_poisoned`), unless it ran out of fuel or time or
+    /// the box is transactional (see `WasmBoxOptions::transactional`), in which case the
+    /// message is rolled back.
+    pub fn message(&mut self, input: &Input) -> Result<(), WasmBoxError> {
+        let result = self.deliver(input);
+        self.dispatch_outbox();
+
+        result
+    }
+
+    /// Deliver a message to the guest and return the output it sends while handling it,
+    /// instead of passing it to the callback or queueing it. Failures are handled as by
+    /// `message`, but any output sent before the guest failed is discarded.
+    pub fn call(&mut self, input: &Input) -> Result<Vec<Output>, WasmBoxError> {
+        let stream = std::mem::replace(&mut self.store.data_mut().stream, false);
+        let result = self.deliver(input);
+        self.store.data_mut().stream = stream;
+        let outputs = std::mem::take(&mut self.store.data_mut().outbox);
+
+        result.map(|()| outputs)
+    }
+
+    /// Deliver a message to the guest, leaving its output in the outbox.
+    fn deliver(&mut self, input: &Input) -> Result<(), WasmBoxError> {
+        self.record(&JournalEntry::Message(input))?;
+        let result = bincode::serialize(input)
+            .map_err(WasmBoxError::from)
+            .and_then(|message| {
+                self.deliver_with(&message, |host, pt, len| {
+                    host.fn_send.call(&mut host.store, (pt, len))
+                })
+            });
+        self.record_outcome(result)
+    }
+
+    /// Pass a serialized message to the guest with `send`, under the limits for messages,
+    /// leaving the guest's output in the outbox.
+    fn deliver_with(
+        &mut self,
+        message: &[u8],
+        send: impl FnOnce(&mut Self, u32, u32) -> Result<(), Trap>,
+    ) -> Result<(), WasmBoxError> {
+        check_message_size(message.len(), self.options.max_message_bytes)?;
+        self.run_guest(|host| host.try_send(message, send))
+    }
+
+    /// Call into the guest with `call`, under the limits for messages, leaving the guest's
+    /// output in the outbox.
+    fn run_guest(
+        &mut self,
+        call: impl FnOnce(&mut Self) -> Result<(), WasmBoxError>,
+    ) -> Result<(), WasmBoxError> {
+        if self.poisoned {
+            return Err(WasmBoxError::Poisoned);
+        }
+        if self.is_finished() {
+            return Err(WasmBoxError::BoxFinished);
+        }
+
+        let (message_fuel, message_deadline) =
+            (self.options.message_fuel, self.options.message_deadline);
+
+        // A guest interrupted part-way through a message may have left its memory in an
+        // inconsistent state, so keep a copy to roll back to.
+        let checkpoint = match (message_fuel, message_deadline, self.options.transactional) {
+            (None, None, false) => None,
+            _ => Some(self.update_checkpoint()?),
+        };
+
+        self.store.data_mut().outstanding_request_ids =
+            self.state.requests().iter().map(HostRequest::id).collect();
+        let result = self.metered(message_fuel, message_deadline, call);
+
+        if result.is_ok() {
+            self.collect_pending();
+            self.checkpoint = checkpoint;
+            return result;
+        }
+
+        // The box stays poisoned if rolling back fails.
+        self.poisoned = true;
+        self.store.data_mut().requests.clear();
+        self.store.data_mut().cancelled_requests.clear();
+        self.store.data_mut().timers.clear();
+        self.store.data_mut().cancelled_timers.clear();
+        self.store.data_mut().exit_value = None;
+        match (&result, checkpoint) {
+            (Err(WasmBoxError::OutOfFuel | WasmBoxError::Timeout), Some(checkpoint)) => {
+                self.store.data_mut().outbox.clear();
+                self.load_snapshot(&checkpoint)?;
+                self.checkpoint = Some(checkpoint);
+            }
+            (Err(_), Some(checkpoint)) if self.options.transactional => {
+                self.rollback(&checkpoint)?;
+                self.checkpoint = Some(checkpoint);
+            }
+            _ => (),
+        }
+
+        result
+    }
+
+    /// Bring the checkpoint up to date with the box's current state and take it. The first
+    /// checkpoint is a full copy of memory; after that, only the pages which differ are
+    /// copied.
+    fn update_checkpoint(&mut self) -> Result<Snapshot, WasmBoxError> {
+        let mut checkpoint = match self.checkpoint.take() {
+            Some(checkpoint) => checkpoint,
+            None => return self.snapshot_state(),
+        };
+
+        checkpoint.update_memory(self.memory.data(&self.store));
+        checkpoint.globals = self.snapshot_globals()?;
+        checkpoint.tables = self.snapshot_tables()?;
+        checkpoint.state = self.state.snapshot();
+
+        Ok(checkpoint)
+    }
+
+    /// Make the requests and timers the guest sent during the last call outstanding, drop the
+    /// requests and timers it cancelled, and record whether it finished.
+    fn collect_pending(&mut self) {
+        let requests = std::mem::take(&mut self.store.data_mut().requests);
+        self.state.add_requests(requests);
+        let cancelled_requests = std::mem::take(&mut self.store.data_mut().cancelled_requests);
+        for id in cancelled_requests {
+            self.state.remove_request(id);
+        }
+        let timers = std::mem::take(&mut self.store.data_mut().timers);
+        self.state.add_timers(timers);
+        let cancelled_timers = std::mem::take(&mut self.store.data_mut().cancelled_timers);
+        self.state.cancel_timers(cancelled_timers);
+        if let Some(exit_value) = self.store.data_mut().exit_value.take() {
+            self.state.set_exit_value(exit_value);
+        }
+    }
+
+    /// Requests the guest has made with `WasmBoxContext::request` which have not yet been
+    /// responded to or cancelled, oldest first. Outstanding requests are included in snapshots.
+    pub fn pending_requests(&self) -> &[HostRequest] {
+        self.state.requests()
+    }
+
+    /// Respond to an outstanding request, resuming the guest code that made it. The response
+    /// is delivered like a message: output the guest sends is passed to the callback, and it
+    /// is subject to the same limits and rollback.
+    pub fn respond<Response: Serialize>(
+        &mut self,
+        id: u32,
+        response: &Response,
+    ) -> Result<(), WasmBoxError> {
+        let response = bincode::serialize(response)?;
+        self.respond_serialized(id, &response)
+    }
+
+    fn respond_serialized(&mut self, id: u32, response: &[u8]) -> Result<(), WasmBoxError> {
+        // Finishing drops the guest's outstanding requests, so check this before looking the
+        // request up.
+        if self.is_finished() {
+            return Err(WasmBoxError::BoxFinished);
+        }
+        let fn_respond = self
+            .fn_respond
+            .ok_or_else(|| WasmBoxError::MissingExport(EXT_FN_RESPOND.into()))?;
+        if !self.state.has_request(id) {
+            return Err(WasmBoxError::UnknownRequest(id));
+        }
+        self.record(&JournalEntry::Respond { id, response })?;
+
+        let result = self.deliver_with(response, |host, pt, len| {
+            fn_respond.call(&mut host.store, (id, pt, len))
+        });
+        if result.is_ok() {
+            self.state.remove_request(id);
+        }
+        self.dispatch_outbox();
+
+        self.record_outcome(result)
+    }
+
+    /// Pass the output the guest sent during the last call to the callback, or queue it.
+    fn dispatch_outbox(&mut self) {
+        let data = self.store.data_mut();
+        let outbox = std::mem::take(&mut data.outbox);
+        match &data.callback {
+            Some(callback) => outbox.into_iter().for_each(callback),
+            None => self.queue.extend(outbox),
+        }
+    }
+
+    /// Take all queued output from a host constructed with `new_with_output_queue` or
+    /// `from_snapshot_with_output_queue`, oldest first.
+    pub fn drain_outputs(&mut self) -> impl Iterator<Item = Output> + '_ {
+        self.queue.drain(..)
+    }
+
+    /// Take the oldest queued output, if any.
+    pub fn next_output(&mut self) -> Option<Output> {
+        self.queue.pop_front()
+    }
+
+    /// Replace the instance with a fresh instance of the same module, restored to
+    /// `checkpoint`. Used when a trap may have left the instance unusable.
+    fn rollback(&mut self, checkpoint: &Snapshot) -> Result<(), WasmBoxError> {
+        let mut fresh = Self::instantiate(&self.module, self.options.clone(), None)?;
+        fresh.store.data_mut().callback = self.store.data_mut().callback.take();
+        fresh.queue = std::mem::take(&mut self.queue);
+        fresh.last_fuel_consumed = self.last_fuel_consumed;
+        fresh.journal = self.journal.take();
+        fresh.journal_options = self.journal_options;
+        *self = fresh;
+
+        self.load_snapshot(checkpoint)
+    }
+
+    fn snapshot_globals(&mut self) -> Result<Vec<(String, GlobalValue)>, WasmBoxError> {
+        let mut globals = Vec::with_capacity(self.globals.len());
+        for (name, global) in &self.globals {
+            let value = GlobalValue::from_val(&global.get(&mut self.store))
+                .ok_or_else(|| WasmBoxError::MistypedExport(name.clone()))?;
+            globals.push((name.clone(), value));
+        }
+
+        Ok(globals)
+    }
+
+    /// The elements of each table. Fails if an element can't be represented: a function
+    /// which the module doesn't export for snapshots, or an `externref` other than null.
+    fn snapshot_tables(&mut self) -> Result<Vec<(String, TableElements)>, WasmBoxError> {
+        let mut tables = Vec::with_capacity(self.tables.len());
+        for (name, table) in &self.tables {
+            let size = table.size(&self.store);
+            let mut elements = Vec::with_capacity(size as usize);
+            for index in 0..size {
+                let func_index = match table.get(&mut self.store, index) {
+                    Some(Val::FuncRef(None) | Val::ExternRef(None)) => {
+                        elements.push(None);
+                        continue;
+                    }
+                    Some(Val::FuncRef(Some(func))) => {
+                        self.func_indices.get(&raw_func(&self.store, &func))
+                    }
+                    _ => None,
+                };
+                let func_index =
+                    func_index.ok_or_else(|| WasmBoxError::UnsupportedTableElement {
+                        table: name.clone(),
+                        index,
+                    })?;
+                elements.push(Some(*func_index));
+            }
+            tables.push((name.clone(), elements));
+        }
+
+        Ok(tables)
+    }
+
+    /// SHA-256 hash of the box's state: its memory, globals, tables and host state, and
+    /// whether it is poisoned. Boxes of the same module with equal hashes behave identically.
+    pub fn state_hash(&mut self) -> Result<[u8; 32], WasmBoxError> {
+        let rest = bincode::serialize(&(
+            self.snapshot_globals()?,
+            self.snapshot_tables()?,
+            self.state.snapshot(),
+            self.poisoned,
+        ))?;
+
+        let mut hasher = Sha256::new();
+        hasher.update(self.memory.data(&self.store));
+        hasher.update(rest);
+
+        Ok(hasher.finalize().into())
+    }
+
+    pub fn snapshot_state(&mut self) -> Result<Snapshot, WasmBoxError> {
         Ok(Snapshot {
-            memory: memory.to_vec(),
+            memory: self.memory.data(&self.store).to_vec(),
+            globals: self.snapshot_globals()?,
+            tables: self.snapshot_tables()?,
             state: self.state.snapshot(),
         })
     }
 
-    pub fn snapshot_to_file(&self, filename: &str) -> anyhow::Result<()> {
-        let snapshot = self.snapshot_state()?;
-        std::fs::write(filename, bincode::serialize(&snapshot)?)?;
+    /// Take a snapshot that stores only the pages of memory which differ from `base`. The
+    /// current state can be reconstructed by applying the result to `base` with
+    /// `Snapshot::apply_delta`.
+    ///
+    /// To checkpoint cheaply after every message, keep a base snapshot around and advance it
+    /// by applying each delta as it is taken; each delta in the resulting chain then only
+    /// holds the pages changed since the previous one.
+    pub fn snapshot_delta(&mut self, base: &Snapshot) -> Result<DeltaSnapshot, WasmBoxError> {
+        let globals = self.snapshot_globals()?;
+        let tables = self.snapshot_tables()?;
+        let memory = self.memory.data(&self.store);
+
+        Ok(DeltaSnapshot {
+            base_memory_len: base.memory.len(),
+            memory_len: memory.len(),
+            pages: snapshot::diff_pages(&base.memory, memory),
+            globals,
+            tables,
+            state: self.state.snapshot(),
+        })
+    }
+
+    pub fn snapshot_to_file(&mut self, filename: &str) -> Result<(), WasmBoxError> {
+        self.snapshot_to_file_with_options(filename, &SnapshotOptions::default())?;
 
         Ok(())
     }
 
-    pub fn restore_snapshot(&mut self, snapshot: &Snapshot) -> anyhow::Result<()> {
-        let mut p = self.memory.data_mut(&mut self.store);
-        p.write_all(&snapshot.memory)?;
+    /// Write a snapshot to a file, encoded according to `options`. Files written this way are
+    /// read by `restore_snapshot_from_file` like any other.
+    pub fn snapshot_to_file_with_options(
+        &mut self,
+        filename: &str,
+        options: &SnapshotOptions,
+    ) -> Result<SnapshotStats, WasmBoxError> {
+        let snapshot = self.snapshot_state()?;
+        let mut file = BufWriter::new(File::create(filename)?);
+        let stats = snapshot.write_to(&mut file, &self.module.hash, options)?;
+        file.flush()?;
+
+        Ok(stats)
+    }
+
+    pub fn restore_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), WasmBoxError> {
+        self.record(&JournalEntry::Restore(snapshot))?;
+        let result = self.load_snapshot(snapshot);
+        self.record_outcome(result)
+    }
+
+    /// Restore a snapshot without journaling it.
+    fn load_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), WasmBoxError> {
+        // Check everything before changing anything, so that an incompatible snapshot leaves
+        // the box as it was.
+        let (snapshot_pages, globals, tables) = self.validate_snapshot(snapshot)?;
+
+        // If applying the snapshot fails part way through, the box is left with a mix of
+        // states.
+        self.poisoned = true;
+
+        // Memory can't shrink, so if the snapshot is smaller than the live memory, the bytes
+        // beyond it are zeroed instead.
+        let current_pages = self.memory.size(&self.store);
+        if snapshot_pages > current_pages {
+            self.memory
+                .grow(&mut self.store, snapshot_pages - current_pages)
+                .map_err(WasmBoxError::Runtime)?;
+        }
+
+        let snapshot_len = snapshot.memory.len();
+        let data = self.memory.data_mut(&mut self.store);
+        data[..snapshot_len].copy_from_slice(&snapshot.memory);
+        data[snapshot_len..].fill(0);
+
+        for (global, value) in globals.into_iter().zip(&snapshot.globals) {
+            global
+                .set(&mut self.store, value.1.to_val())
+                .map_err(WasmBoxError::Runtime)?;
+        }
+
+        // Tables can't shrink either, so elements beyond the snapshot's are set to null.
+        for (table, (_, elements)) in tables.into_iter().zip(&snapshot.tables) {
+            let null = match table.ty(&self.store).element() {
+                ValType::ExternRef => Val::ExternRef(None),
+                _ => Val::FuncRef(None),
+            };
+            let size = elements.len() as u32;
+            let current_size = table.size(&self.store);
+            if size > current_size {
+                table
+                    .grow(&mut self.store, size - current_size, null.clone())
+                    .map_err(WasmBoxError::Runtime)?;
+            } else {
+                table
+                    .fill(&mut self.store, size, null.clone(), current_size - size)
+                    .map_err(WasmBoxError::Runtime)?;
+            }
+
+            for (index, element) in elements.iter().enumerate() {
+                let value = match element {
+                    Some(func_index) => Val::FuncRef(Some(self.funcs[func_index])),
+                    None => null.clone(),
+                };
+                table
+                    .set(&mut self.store, index as u32, value)
+                    .map_err(WasmBoxError::Runtime)?;
+            }
+        }
 
         self.state.load_snapshot(&snapshot.state);
+        self.poisoned = false;
 
         Ok(())
     }
 
-    pub fn restore_snapshot_from_file(&mut self, filename: &str) -> anyhow::Result<()> {
-        let contents: Snapshot = bincode::deserialize_from(File::open(filename)?)?;
+    /// Check that `snapshot` can be loaded into this box, returning the number of pages of
+    /// memory it needs and the globals and tables it sets, in the snapshot's order.
+    fn validate_snapshot(
+        &self,
+        snapshot: &Snapshot,
+    ) -> Result<(u64, Vec<Global>, Vec<Table>), WasmBoxError> {
+        let snapshot_len = snapshot.memory.len();
+        if !snapshot_len.is_multiple_of(WASM_PAGE_SIZE) {
+            return Err(WasmBoxError::IncompatibleSnapshot(format!(
+                "Snapshot memory is {} bytes, which is not a whole number of pages.",
+                snapshot_len
+            )));
+        }
+
+        let snapshot_pages = (snapshot_len / WASM_PAGE_SIZE) as u64;
+        let maximum = self.memory.ty(&self.store).maximum();
+        let limit = self
+            .options
+            .max_memory_bytes
+            .map(|max| (max / WASM_PAGE_SIZE) as u64);
+        if let Some(maximum) = maximum.into_iter().chain(limit).min() {
+            if snapshot_pages > maximum.max(self.memory.size(&self.store)) {
+                return Err(WasmBoxError::IncompatibleSnapshot(format!(
+                    "Snapshot memory is {} pages, but the module allows at most {}.",
+                    snapshot_pages, maximum
+                )));
+            }
+        }
+
+        let mut globals = Vec::with_capacity(snapshot.globals.len());
+        for (name, value) in &snapshot.globals {
+            let (_, global) = self
+                .globals
+                .iter()
+                .find(|(n, _)| n == name)
+                .ok_or_else(|| {
+                    WasmBoxError::IncompatibleSnapshot(format!(
+                        "Snapshot has global {} which module lacks.",
+                        name
+                    ))
+                })?;
+            if *global.ty(&self.store).content() != value.ty() {
+                return Err(WasmBoxError::IncompatibleSnapshot(format!(
+                    "Snapshot has a value of the wrong type for global {}.",
+                    name
+                )));
+            }
+            globals.push(*global);
+        }
+
+        let mut tables = Vec::with_capacity(snapshot.tables.len());
+        for (name, elements) in &snapshot.tables {
+            let (_, table) = self.tables.iter().find(|(n, _)| n == name).ok_or_else(|| {
+                WasmBoxError::IncompatibleSnapshot(format!(
+                    "Snapshot has table {} which module lacks.",
+                    name
+                ))
+            })?;
+            let size = u32::try_from(elements.len()).map_err(|_| {
+                WasmBoxError::IncompatibleSnapshot(format!(
+                    "Snapshot has {} elements in table {}.",
+                    elements.len(),
+                    name
+                ))
+            })?;
+            let maximum = table.ty(&self.store).maximum();
+            if let Some(maximum) = maximum
+                .into_iter()
+                .chain(self.options.max_table_elements)
+                .min()
+            {
+                if size > maximum.max(table.size(&self.store)) {
+                    return Err(WasmBoxError::IncompatibleSnapshot(format!(
+                        "Snapshot has {} elements in table {}, but the module allows at most {}.",
+                        size, name, maximum
+                    )));
+                }
+            }
+            let holds_funcs = table.ty(&self.store).element() == ValType::FuncRef;
+            for func_index in elements.iter().flatten() {
+                if !holds_funcs || !self.funcs.contains_key(func_index) {
+                    return Err(WasmBoxError::IncompatibleSnapshot(format!(
+                        "Snapshot has function {} in table {}, which the module can't hold there.",
+                        func_index, name
+                    )));
+                }
+            }
+            tables.push(*table);
+        }
+
+        Ok((snapshot_pages, globals, tables))
+    }
+
+    /// Restore the state obtained by applying a chain of delta snapshots, in order, to `base`.
+    pub fn restore_snapshot_with_deltas(
+        &mut self,
+        base: &Snapshot,
+        deltas: &[DeltaSnapshot],
+    ) -> Result<(), WasmBoxError> {
+        let mut snapshot = base.clone();
+        for delta in deltas {
+            snapshot.apply_delta(delta)?;
+        }
+
+        self.restore_snapshot(&snapshot)
+    }
+
+    pub fn restore_snapshot_from_file(&mut self, filename: &str) -> Result<(), WasmBoxError> {
+        let file = BufReader::new(File::open(filename)?);
+        let contents = Snapshot::read_from(file, &self.module.hash)?;
         self.restore_snapshot(&contents)?;
 
         Ok(())
     }
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct Snapshot {
-    memory: Vec<u8>,
-    state: WasmBoxStateSnapshot,
+impl<Input: Serialize + DeserializeOwned, Output: DeserializeOwned> WasmBoxHost<Input, Output> {
+    /// Apply the events recorded by `set_journal` to the box, in order. To rebuild a box,
+    /// construct it with `from_snapshot` (or a variant) from the snapshot it was in when
+    /// journaling started, then replay its journal.
+    ///
+    /// Messages and responses which failed when they were recorded fail again when replayed,
+    /// with the same effect on the box, so their errors are not returned. Timeouts depend on
+    /// the speed of the machine rather than on the box's state, so the deadline for messages
+    /// is lifted during replay, and an event which timed out when it was recorded times out
+    /// again without running the guest. Only errors reading the journal, or restoring a
+    /// snapshot from it, stop the replay. If this box is itself journaling, the replayed
+    /// events are appended to its journal.
+    pub fn replay_journal(&mut self, journal: impl std::io::Read) -> Result<(), WasmBoxError> {
+        let mut journal = BufReader::new(journal);
+        journal::read_header(&mut journal, &self.module.hash)?;
+        let mut journal = JournalReader::new(journal);
+
+        let message_deadline = self.options.message_deadline;
+        self.set_message_deadline(None);
+        let result = loop {
+            match journal.next_event() {
+                Ok(Some((event, outcome))) => {
+                    if let Err(error) = self.apply_event(&event, outcome) {
+                        break Err(error);
+                    }
+                }
+                Ok(None) => break Ok(()),
+                Err(error) => break Err(error),
+            }
+        };
+        self.set_message_deadline(message_deadline);
+
+        result
+    }
+
+    /// Apply an event read from a journal. Guest errors are ignored, since they are part of
+    /// the replay.
+    fn apply_event(
+        &mut self,
+        event: &JournalEvent<Input>,
+        outcome: Option<Outcome>,
+    ) -> Result<(), WasmBoxError> {
+        if outcome.is_some_and(|outcome| outcome.timed_out) {
+            return self.replay_timeout(event);
+        }
+
+        match event {
+            JournalEvent::Message(input) => {
+                let _ = self.message(input);
+            }
+            JournalEvent::SetTime(time) => self.set_time(*time)?,
+            JournalEvent::AdvanceTime(time) => {
+                let _ = self.advance_time(*time);
+            }
+            JournalEvent::Restore(snapshot) => self.restore_snapshot(snapshot)?,
+            JournalEvent::Respond { id, response } => {
+                let _ = self.respond_serialized(*id, response);
+            }
+            JournalEvent::Outcome(_) => (),
+        }
+
+        Ok(())
+    }
+
+    /// Reproduce the effect of an event which timed out when it was recorded. A message
+    /// which times out is rolled back, so apart from the time set by `advance_time`, the box
+    /// is left as it was.
+    fn replay_timeout(&mut self, event: &JournalEvent<Input>) -> Result<(), WasmBoxError> {
+        match event {
+            JournalEvent::Message(input) => self.record(&JournalEntry::Message(input))?,
+            JournalEvent::AdvanceTime(time) => {
+                self.record(&JournalEntry::AdvanceTime(*time))?;
+                self.state.set_time(*time);
+            }
+            JournalEvent::Respond { id, response } => {
+                self.record(&JournalEntry::Respond { id: *id, response })?
+            }
+            _ => {
+                return Err(WasmBoxError::InvalidJournal(format!(
+                    "Event {} can't time out.",
+                    event.describe()
+                )))
+            }
+        }
+
+        let _ = self.record_outcome(Err(WasmBoxError::Timeout));
+        Ok(())
+    }
 }
@@ -0,0 +1,107 @@
+use crate::instrument;
+use crate::snapshot::{module_hash, ModuleHash};
+use crate::WasmBoxError;
+use std::sync::Mutex;
+use std::time::Duration;
+use wasmtime::{Config, Engine, Module};
+
+/// Interval at which the engine's epoch is incremented, which is the granularity of
+/// deadlines on guest calls.
+pub const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+static ENGINE: Mutex<Option<Engine>> = Mutex::new(None);
+
+/// Identifies a file as a module compiled by `prepare_module`. It is followed by the hash of
+/// the module the file was compiled from, and then by wasmtime's compiled module.
+pub(crate) const COMPILED_MODULE_MAGIC: [u8; 8] = *b"WBMODULE";
+
+/// Take a module in binary or text format and instrument it so that its globals and tables
+/// can be snapshotted.
+pub fn instrument_module(module: &[u8]) -> Result<Vec<u8>, WasmBoxError> {
+    let module =
+        wat::parse_bytes(module).map_err(|error| WasmBoxError::InvalidModule(error.to_string()))?;
+
+    instrument::export_state(&module)
+}
+
+/// Get the engine used for running guest modules. Modules must be compiled and run with the
+/// same configuration, so this is used both by `prepare_module` and when loading.
+///
+/// The engine is shared by all modules so that a single background thread can drive the
+/// epoch used for deadlines.
+pub fn engine() -> Result<Engine, WasmBoxError> {
+    let mut engine = ENGINE
+        .lock()
+        .expect("Something panicked while creating the engine.");
+    if let Some(engine) = &*engine {
+        return Ok(engine.clone());
+    }
+
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    config.epoch_interruption(true);
+    let new_engine = Engine::new(&config).map_err(WasmBoxError::Runtime)?;
+
+    let ticker_engine = new_engine.clone();
+    std::thread::Builder::new()
+        .name("wasmbox-epoch".to_string())
+        .spawn(move || loop {
+            std::thread::sleep(EPOCH_TICK);
+            ticker_engine.increment_epoch();
+        })?;
+
+    *engine = Some(new_engine.clone());
+    Ok(new_engine)
+}
+
+/// A compiled guest module. Loading and compiling a module is expensive, so when running
+/// many boxes of the same module, load it once and construct each `WasmBoxHost` from it.
+///
+/// Cloning is cheap; clones share the underlying compiled code.
+#[derive(Clone)]
+pub struct WasmBoxModule {
+    pub(crate) engine: Engine,
+    pub(crate) module: Module,
+    pub(crate) hash: ModuleHash,
+}
+
+impl WasmBoxModule {
+    /// Load a module from a `.wasm` (or `.wat`) file, compiling it.
+    pub fn from_wasm_file(module_file: &str) -> Result<Self, WasmBoxError> {
+        let engine = engine()?;
+        let module_bytes = std::fs::read(module_file)?;
+        let module = Module::new(&engine, instrument_module(&module_bytes)?)
+            .map_err(WasmBoxError::Module)?;
+
+        Ok(WasmBoxModule {
+            engine,
+            module,
+            hash: module_hash(&module_bytes),
+        })
+    }
+
+    /// Load a module that was compiled ahead of time by `prepare_module`.
+    ///
+    /// The module has the same hash as the file it was compiled from, so snapshots and
+    /// journals can be shared between hosts that load either one.
+    pub fn from_compiled_module(module_file: &str) -> Result<Self, WasmBoxError> {
+        let engine = engine()?;
+        let module_bytes = std::fs::read(module_file)?;
+
+        let hash_end = COMPILED_MODULE_MAGIC.len() + std::mem::size_of::<ModuleHash>();
+        if module_bytes.len() < hash_end || !module_bytes.starts_with(&COMPILED_MODULE_MAGIC) {
+            return Err(WasmBoxError::NotCompiledModule(module_file.into()));
+        }
+        let mut hash = ModuleHash::default();
+        hash.copy_from_slice(&module_bytes[COMPILED_MODULE_MAGIC.len()..hash_end]);
+
+        let module = unsafe { Module::deserialize(&engine, &module_bytes[hash_end..]) }
+            .map_err(WasmBoxError::Module)?;
+
+        Ok(WasmBoxModule {
+            engine,
+            module,
+            hash,
+        })
+    }
+}
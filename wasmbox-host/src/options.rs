@@ -0,0 +1,43 @@
+use crate::HostFunctions;
+use std::time::Duration;
+
+/// Options for constructing a `WasmBoxHost`. The defaults impose no limits.
+#[derive(Clone, Debug, Default)]
+pub struct WasmBoxOptions {
+    /// Maximum fuel the guest may consume while initializing. Most WebAssembly instructions
+    /// consume one unit of fuel. The module's start function, if it has one, and
+    /// `wasmbox_initialize` are each given this much.
+    pub init_fuel: Option<u64>,
+    /// Maximum fuel the guest may consume while handling each message. A message which runs
+    /// out of fuel is rolled back like one which times out, at the same cost.
+    pub message_fuel: Option<u64>,
+    /// Maximum wall-clock time the guest may spend initializing. As with `init_fuel`, the
+    /// start function and `wasmbox_initialize` are each given this long.
+    pub init_deadline: Option<Duration>,
+    /// Maximum wall-clock time the guest may spend handling each message. When a message
+    /// times out, the box is rolled back to its state before the message. Deadlines are
+    /// enforced at a granularity of about 10 milliseconds.
+    ///
+    /// To roll back, the host keeps a copy of the guest's memory, and compares it with the
+    /// live memory page by page before each message to copy the pages that changed.
+    pub message_deadline: Option<Duration>,
+    /// Maximum size the guest's linear memory may grow to, in bytes.
+    pub max_memory_bytes: Option<usize>,
+    /// Maximum number of elements each of the guest's tables may grow to.
+    pub max_table_elements: Option<u32>,
+    /// Maximum size of a serialized message to or from the guest, in bytes. A guest which
+    /// sends a larger message traps.
+    pub max_message_bytes: Option<usize>,
+    /// If set, a message which makes the guest trap is rolled back: the module is
+    /// re-instantiated and restored to its state before the message, and any output the guest
+    /// sent while handling it is discarded. Like `message_deadline`, this costs a copy of the
+    /// guest's memory, kept up to date before each message.
+    pub transactional: bool,
+    /// Functions the guest can call with `call_host`. More can be added after construction
+    /// with `WasmBoxHost::register_host_fn`.
+    pub host_functions: HostFunctions,
+    /// Seed for the guest's random number generator. Boxes with the same seed see the same
+    /// sequence of random numbers; use `derive_rng_seed` to give each box its own. If `None`,
+    /// a fixed default seed is used.
+    pub rng_seed: Option<[u8; 32]>,
+}
@@ -0,0 +1,52 @@
+//! Requests which asynchronous guests make to the host, and which the host answers later with
+//! `WasmBoxHost::respond`.
+
+use crate::store::StoreData;
+use crate::{check_message_size, get_memory, get_u8_vec, WasmBoxError};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use wasmtime::Caller;
+
+/// A request the guest made with `WasmBoxContext::request`, awaiting a response from the host.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HostRequest {
+    id: u32,
+    payload: Vec<u8>,
+}
+
+impl HostRequest {
+    /// Identifies the request when responding to it. Unique among the box's outstanding
+    /// requests.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Deserialize the request, which must have the type the guest sent.
+    pub fn decode<Request: DeserializeOwned>(&self) -> Result<Request, WasmBoxError> {
+        Ok(bincode::deserialize(&self.payload)?)
+    }
+}
+
+/// Record a request made by the guest during the current call. Its id must not be that of an
+/// outstanding request, since responding removes the request by id.
+pub fn receive_request<Output>(
+    caller: &mut Caller<'_, StoreData<Output>>,
+    id: u32,
+    (ptr, len): (u32, u32),
+    max_message_bytes: Option<usize>,
+) -> Result<(), WasmBoxError> {
+    check_message_size(len as usize, max_message_bytes)?;
+    let data = caller.data();
+    if data.outstanding_request_ids.contains(&id)
+        || data.requests.iter().any(|request| request.id == id)
+    {
+        return Err(WasmBoxError::Abi(format!(
+            "Guest made request {} while a request with that id is outstanding.",
+            id
+        )));
+    }
+    let memory = get_memory(caller)?;
+    let payload = get_u8_vec(caller, &memory, ptr, len)?.to_vec();
+    caller.data_mut().requests.push(HostRequest { id, payload });
+
+    Ok(())
+}
@@ -0,0 +1,425 @@
+use crate::state::WasmBoxStateSnapshot;
+use crate::{WasmBoxError, WasmBoxModule};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{Read, Write};
+use std::time::SystemTime;
+use wasmtime::{Val, ValType};
+
+/// Identifies a file as a WasmBox snapshot.
+const SNAPSHOT_MAGIC: [u8; 8] = *b"WASMBOX\0";
+
+/// Version of the snapshot file format. Increment whenever the layout of `SnapshotHeader` or
+/// `Snapshot` changes.
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 10;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Largest uncompressed body accepted from a snapshot file, so that a corrupt or malicious
+/// file can't make the host decompress without bound. A wasm32 guest's memory is at most
+/// 4 GiB; this allows as much again for the rest of its state.
+const MAX_UNCOMPRESSED_LEN: u64 = 8 << 30;
+
+/// SHA-256 hash of the module file a host was loaded from.
+pub type ModuleHash = [u8; 32];
+
+pub fn module_hash(module: &[u8]) -> ModuleHash {
+    Sha256::digest(module).into()
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Error for a snapshot file whose contents can't be decoded.
+fn corrupt(error: impl std::fmt::Display) -> WasmBoxError {
+    WasmBoxError::IncompatibleSnapshot(format!("Snapshot file is corrupt: {}", error))
+}
+
+/// The value of a mutable global, stored by bit pattern so that floats round-trip exactly.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GlobalValue {
+    I32(i32),
+    I64(i64),
+    F32(u32),
+    F64(u64),
+}
+
+impl GlobalValue {
+    pub fn from_val(val: &Val) -> Option<GlobalValue> {
+        match val {
+            Val::I32(v) => Some(GlobalValue::I32(*v)),
+            Val::I64(v) => Some(GlobalValue::I64(*v)),
+            Val::F32(v) => Some(GlobalValue::F32(*v)),
+            Val::F64(v) => Some(GlobalValue::F64(*v)),
+            _ => None,
+        }
+    }
+
+    pub fn ty(&self) -> ValType {
+        match self {
+            GlobalValue::I32(_) => ValType::I32,
+            GlobalValue::I64(_) => ValType::I64,
+            GlobalValue::F32(_) => ValType::F32,
+            GlobalValue::F64(_) => ValType::F64,
+        }
+    }
+
+    pub fn to_val(self) -> Val {
+        match self {
+            GlobalValue::I32(v) => Val::I32(v),
+            GlobalValue::I64(v) => Val::I64(v),
+            GlobalValue::F32(v) => Val::F32(v),
+            GlobalValue::F64(v) => Val::F64(v),
+        }
+    }
+}
+
+/// The elements of a table, each either null or a function, by its index in the module.
+pub type TableElements = Vec<Option<u32>>;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Snapshot {
+    pub(crate) memory: Vec<u8>,
+    /// Mutable globals, by export name.
+    pub(crate) globals: Vec<(String, GlobalValue)>,
+    /// Table elements, by export name.
+    pub(crate) tables: Vec<(String, TableElements)>,
+    pub(crate) state: WasmBoxStateSnapshot,
+}
+
+/// Granularity at which delta snapshots compare and store memory.
+pub const DELTA_PAGE_SIZE: usize = 4096;
+
+/// The difference between two snapshots, storing only the pages of memory that changed.
+/// Globals, tables and host state are stored in full.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DeltaSnapshot {
+    pub(crate) base_memory_len: usize,
+    pub(crate) memory_len: usize,
+    /// Changed pages, by page index. The last page may be shorter than `DELTA_PAGE_SIZE`.
+    pub(crate) pages: Vec<(u32, Vec<u8>)>,
+    pub(crate) globals: Vec<(String, GlobalValue)>,
+    pub(crate) tables: Vec<(String, TableElements)>,
+    pub(crate) state: WasmBoxStateSnapshot,
+}
+
+impl DeltaSnapshot {
+    /// Number of pages of memory stored in this delta.
+    pub fn changed_pages(&self) -> usize {
+        self.pages.len()
+    }
+}
+
+/// Find the pages of `target` that differ from `base`. Memory beyond the end of `base` is
+/// treated as zeroed.
+pub fn diff_pages(base: &[u8], target: &[u8]) -> Vec<(u32, Vec<u8>)> {
+    target
+        .chunks(DELTA_PAGE_SIZE)
+        .enumerate()
+        .filter(|(index, page)| {
+            let start = index * DELTA_PAGE_SIZE;
+            match base.get(start..start + page.len()) {
+                Some(base_page) => base_page != *page,
+                None => {
+                    let base_rest = base.get(start..).unwrap_or_default();
+                    page[..base_rest.len()] != *base_rest
+                        || page[base_rest.len()..].iter().any(|&b| b != 0)
+                }
+            }
+        })
+        .map(|(index, page)| (index as u32, page.to_vec()))
+        .collect()
+}
+
+/// Compression applied to the body of a snapshot file.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+    Lz4,
+}
+
+/// Options controlling how a snapshot is encoded when written to a file.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SnapshotOptions {
+    /// Omit pages of memory which are entirely zero. Linear memory is usually mostly zeroes,
+    /// so this is cheap and effective even without compression.
+    pub elide_zero_pages: bool,
+    pub compression: Compression,
+}
+
+/// How the body of a snapshot file is encoded, recorded in its header.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SnapshotEncoding {
+    /// If set, the body is a `DeltaSnapshot` against empty memory rather than a `Snapshot`.
+    pub sparse: bool,
+    pub compression: Compression,
+}
+
+/// Sizes measured while writing a snapshot file.
+#[derive(Clone, Copy, Debug)]
+pub struct SnapshotStats {
+    /// Size of the guest's linear memory.
+    pub memory_bytes: usize,
+    /// Number of pages of `DELTA_PAGE_SIZE` bytes omitted because they were zero.
+    pub elided_pages: usize,
+    /// Size of the body before compression.
+    pub uncompressed_bytes: usize,
+    /// Size of the body as written to the file.
+    pub compressed_bytes: usize,
+}
+
+/// Metadata written at the start of a snapshot file, after the magic number and format version.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SnapshotHeader {
+    /// Hash of the module file of the host that took the snapshot.
+    pub module_hash: ModuleHash,
+    /// Wall-clock time the snapshot was taken, in milliseconds since the Unix epoch.
+    pub created_at: u64,
+    pub encoding: SnapshotEncoding,
+    /// Size of the encoded snapshot before compression.
+    pub uncompressed_len: u64,
+    /// SHA-256 hash of the encoded snapshot that follows the header.
+    pub checksum: [u8; 32],
+}
+
+impl SnapshotHeader {
+    /// Read the magic number, format version and header from the start of a snapshot file.
+    pub fn read_from<R: Read>(mut reader: R) -> Result<SnapshotHeader, WasmBoxError> {
+        let mut magic = [0; SNAPSHOT_MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if magic != SNAPSHOT_MAGIC {
+            return Err(WasmBoxError::IncompatibleSnapshot(
+                "Not a WasmBox snapshot file.".into(),
+            ));
+        }
+
+        let mut version = [0; 4];
+        reader.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != SNAPSHOT_FORMAT_VERSION {
+            return Err(WasmBoxError::IncompatibleSnapshot(format!(
+                "Snapshot file has format version {}, but this host only reads version {}.",
+                version, SNAPSHOT_FORMAT_VERSION
+            )));
+        }
+
+        bincode::deserialize_from(reader).map_err(corrupt)
+    }
+
+    pub fn from_file(filename: &str) -> Result<SnapshotHeader, WasmBoxError> {
+        SnapshotHeader::read_from(std::io::BufReader::new(std::fs::File::open(filename)?))
+    }
+}
+
+impl Snapshot {
+    /// Compute the delta that turns this snapshot into `target`.
+    pub fn delta_to(&self, target: &Snapshot) -> DeltaSnapshot {
+        DeltaSnapshot {
+            base_memory_len: self.memory.len(),
+            memory_len: target.memory.len(),
+            pages: diff_pages(&self.memory, &target.memory),
+            globals: target.globals.clone(),
+            tables: target.tables.clone(),
+            state: target.state.clone(),
+        }
+    }
+
+    /// Bring this snapshot's memory up to date with `memory`, copying only the pages which
+    /// differ.
+    pub(crate) fn update_memory(&mut self, memory: &[u8]) {
+        self.memory.resize(memory.len(), 0);
+        for (page, live) in self
+            .memory
+            .chunks_mut(DELTA_PAGE_SIZE)
+            .zip(memory.chunks(DELTA_PAGE_SIZE))
+        {
+            if page != live {
+                page.copy_from_slice(live);
+            }
+        }
+    }
+
+    /// Apply a delta taken against this snapshot, turning it into the snapshot the delta
+    /// was taken from.
+    pub fn apply_delta(&mut self, delta: &DeltaSnapshot) -> Result<(), WasmBoxError> {
+        if delta.base_memory_len != self.memory.len() {
+            return Err(WasmBoxError::IncompatibleSnapshot(format!(
+                "Delta was taken against a snapshot with {} bytes of memory, but this snapshot has {}.",
+                delta.base_memory_len,
+                self.memory.len()
+            )));
+        }
+
+        self.memory.resize(delta.memory_len, 0);
+        for (index, page) in &delta.pages {
+            let start = *index as usize * DELTA_PAGE_SIZE;
+            self.memory
+                .get_mut(start..start + page.len())
+                .ok_or_else(|| {
+                    WasmBoxError::IncompatibleSnapshot(format!(
+                        "Delta page {} is out of bounds.",
+                        index
+                    ))
+                })?
+                .copy_from_slice(page);
+        }
+
+        self.globals = delta.globals.clone();
+        self.tables = delta.tables.clone();
+        self.state = delta.state.clone();
+
+        Ok(())
+    }
+
+    /// Express the snapshot as a delta against empty memory, which omits zero pages.
+    fn to_sparse(&self) -> DeltaSnapshot {
+        DeltaSnapshot {
+            base_memory_len: 0,
+            memory_len: self.memory.len(),
+            pages: diff_pages(&[], &self.memory),
+            globals: self.globals.clone(),
+            tables: self.tables.clone(),
+            state: self.state.clone(),
+        }
+    }
+
+    /// Build a snapshot from a delta taken against empty memory.
+    fn from_sparse(sparse: DeltaSnapshot) -> Result<Snapshot, WasmBoxError> {
+        let mut snapshot = Snapshot {
+            memory: Vec::new(),
+            globals: Vec::new(),
+            tables: Vec::new(),
+            state: sparse.state.clone(),
+        };
+        snapshot.apply_delta(&sparse)?;
+
+        Ok(snapshot)
+    }
+
+    /// Write the snapshot in the snapshot file format, recording the module it belongs to.
+    pub fn write_to<W: Write>(
+        &self,
+        mut writer: W,
+        module_hash: &ModuleHash,
+        options: &SnapshotOptions,
+    ) -> Result<SnapshotStats, WasmBoxError> {
+        let (body, elided_pages) = if options.elide_zero_pages {
+            let sparse = self.to_sparse();
+            let total_pages = self.memory.len().div_ceil(DELTA_PAGE_SIZE);
+            let elided_pages = total_pages - sparse.pages.len();
+            (bincode::serialize(&sparse)?, elided_pages)
+        } else {
+            (bincode::serialize(self)?, 0)
+        };
+        let uncompressed_bytes = body.len();
+
+        let body = match options.compression {
+            Compression::None => body,
+            Compression::Zstd => zstd::stream::encode_all(body.as_slice(), ZSTD_LEVEL)?,
+            Compression::Lz4 => lz4_flex::compress_prepend_size(&body),
+        };
+
+        let created_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+
+        let header = SnapshotHeader {
+            module_hash: *module_hash,
+            created_at,
+            encoding: SnapshotEncoding {
+                sparse: options.elide_zero_pages,
+                compression: options.compression,
+            },
+            uncompressed_len: uncompressed_bytes as u64,
+            checksum: Sha256::digest(&body).into(),
+        };
+
+        writer.write_all(&SNAPSHOT_MAGIC)?;
+        writer.write_all(&SNAPSHOT_FORMAT_VERSION.to_le_bytes())?;
+        bincode::serialize_into(&mut writer, &header)?;
+        writer.write_all(&body)?;
+
+        Ok(SnapshotStats {
+            memory_bytes: self.memory.len(),
+            elided_pages,
+            uncompressed_bytes,
+            compressed_bytes: body.len(),
+        })
+    }
+
+    /// Read a snapshot in the snapshot file format, refusing it if it was taken from a
+    /// different module or has been corrupted.
+    pub fn read_from<R: Read>(
+        mut reader: R,
+        module_hash: &ModuleHash,
+    ) -> Result<Snapshot, WasmBoxError> {
+        let header = SnapshotHeader::read_from(&mut reader)?;
+        if header.module_hash != *module_hash {
+            return Err(WasmBoxError::IncompatibleSnapshot(format!(
+                "Snapshot was taken from module {}, but the loaded module is {}.",
+                hex(&header.module_hash),
+                hex(module_hash)
+            )));
+        }
+
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body)?;
+        let checksum: [u8; 32] = Sha256::digest(&body).into();
+        if checksum != header.checksum {
+            return Err(WasmBoxError::IncompatibleSnapshot(
+                "Snapshot file is corrupt: checksum does not match.".into(),
+            ));
+        }
+
+        let uncompressed_len = header.uncompressed_len;
+        if uncompressed_len > MAX_UNCOMPRESSED_LEN {
+            return Err(corrupt(format!(
+                "uncompressed size of {} bytes is too large",
+                uncompressed_len
+            )));
+        }
+        let body = match header.encoding.compression {
+            Compression::None => body,
+            Compression::Zstd => {
+                // Read one byte past the expected size, to tell if there is more.
+                let mut decoded = Vec::with_capacity(uncompressed_len as usize);
+                zstd::stream::read::Decoder::new(body.as_slice())?
+                    .take(uncompressed_len + 1)
+                    .read_to_end(&mut decoded)
+                    .map_err(corrupt)?;
+                decoded
+            }
+            Compression::Lz4 => {
+                // The size prepended by lz4_flex must agree with the header before it is used
+                // to allocate.
+                let prepended = body.get(..4).map(|size| {
+                    u32::from_le_bytes(size.try_into().expect("Slice is 4 bytes.")) as u64
+                });
+                if prepended != Some(uncompressed_len) {
+                    return Err(corrupt("compressed size does not match header"));
+                }
+                lz4_flex::decompress_size_prepended(&body).map_err(corrupt)?
+            }
+        };
+        if body.len() as u64 != uncompressed_len {
+            return Err(corrupt("uncompressed size does not match header"));
+        }
+
+        if header.encoding.sparse {
+            Snapshot::from_sparse(bincode::deserialize(&body).map_err(corrupt)?)
+        } else {
+            bincode::deserialize(&body).map_err(corrupt)
+        }
+    }
+
+    /// Read a snapshot file, refusing it if it was not taken from `module`.
+    pub fn from_file(filename: &str, module: &WasmBoxModule) -> Result<Snapshot, WasmBoxError> {
+        let file = std::io::BufReader::new(std::fs::File::open(filename)?);
+
+        Snapshot::read_from(file, &module.hash)
+    }
+}
@@ -1,23 +1,62 @@
-use ambient_authority::ambient_authority;
+use crate::HostRequest;
 use cap_primitives::time::{Instant, SystemClock, SystemTime};
 use rand_chacha::ChaCha12Rng;
 use rand_core::{RngCore, SeedableRng};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
+    collections::BTreeMap,
     sync::{
         atomic::{AtomicU64, Ordering},
-        Arc, Mutex,
+        Arc, Mutex, OnceLock,
     },
     time::Duration,
 };
-use wasi_common::{WasiClocks, WasiCtx, WasiSystemClock};
+use wasi_common::{WasiClocks, WasiCtx, WasiMonotonicClock, WasiSystemClock};
 use wasmtime_wasi::WasiCtxBuilder;
 
 const MUTEX_ERROR: &str = "Something panicked while holding the mutex, so we can't safely resume.";
 
+/// Seeds the guest's random number generator when no seed is given.
+const DEFAULT_RNG_SEED: [u8; 32] = [
+    228, 89, 231, 220, 224, 20, 162, 27, 133, 157, 88, 214, 45, 102, 132, 24, 70, 0, 72, 252, 102,
+    134, 132, 205, 244, 168, 130, 198, 122, 100, 17, 29,
+];
+
+/// Derive a seed for a box's random number generator from an identifier for the box and a
+/// secret master key, so that each box gets its own stream of random numbers without having
+/// to store its seed separately.
+pub fn derive_rng_seed(master_key: &[u8], box_id: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"wasmbox-rng-seed\0");
+    hasher.update((master_key.len() as u64).to_le_bytes());
+    hasher.update(master_key);
+    hasher.update(box_id.as_bytes());
+
+    hasher.finalize().into()
+}
+
+/// The guest's monotonic clock reads as the time elapsed since its creation time, so any
+/// fixed instant will do as the creation time. Guests only see differences from it.
+static MONOTONIC_ANCHOR: OnceLock<std::time::Instant> = OnceLock::new();
+
+fn monotonic_anchor() -> Instant {
+    Instant::from_std(*MONOTONIC_ANCHOR.get_or_init(std::time::Instant::now))
+}
+
 pub struct WasmBoxState {
     time: Arc<AtomicU64>,
+    /// The latest time the host has set, which the monotonic clock reports so that it never
+    /// goes backwards.
+    monotonic_time: Arc<AtomicU64>,
     rng: DummyRng,
+    /// Requests the guest has made which the host has not yet responded to.
+    requests: Vec<HostRequest>,
+    /// Deadlines of the timers the guest has set which have not yet fired or been cancelled,
+    /// with the number of timers set for each.
+    timers: BTreeMap<u64, u32>,
+    /// The serialized value the guest finished with, once it has finished.
+    exit_value: Option<Vec<u8>>,
 }
 
 #[derive(Clone)]
@@ -46,24 +85,30 @@ impl RngCore for DummyRng {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct WasmBoxStateSnapshot {
     time: u64,
+    monotonic_time: u64,
+    /// The generator's full state, including its seed.
     rng: ChaCha12Rng,
+    requests: Vec<HostRequest>,
+    timers: BTreeMap<u64, u32>,
+    exit_value: Option<Vec<u8>>,
 }
 
 impl WasmBoxState {
-    pub fn new() -> WasmBoxState {
-        let rng = ChaCha12Rng::from_seed([
-            228, 89, 231, 220, 224, 20, 162, 27, 133, 157, 88, 214, 45, 102, 132, 24, 70, 0, 72,
-            252, 102, 134, 132, 205, 244, 168, 130, 198, 122, 100, 17, 29,
-        ]);
+    pub fn new(rng_seed: Option<[u8; 32]>) -> WasmBoxState {
+        let rng = ChaCha12Rng::from_seed(rng_seed.unwrap_or(DEFAULT_RNG_SEED));
 
         WasmBoxState {
             time: Arc::default(),
+            monotonic_time: Arc::default(),
             rng: DummyRng {
                 inner_rng: Arc::new(Mutex::new(rng)),
             },
+            requests: Vec::new(),
+            timers: BTreeMap::new(),
+            exit_value: None,
         }
     }
 
@@ -79,10 +124,8 @@ impl WasmBoxState {
 
         wasi.clocks = WasiClocks {
             system: Box::new(FakeSystemClock::new(self.time.clone())),
-            monotonic: Box::new(wasmtime_wasi::sync::clocks::MonotonicClock::new(
-                ambient_authority(),
-            )),
-            creation_time: Instant::from_std(std::time::Instant::now()),
+            monotonic: Box::new(FakeMonotonicClock::new(self.monotonic_time.clone())),
+            creation_time: monotonic_anchor(),
         };
 
         wasi
@@ -91,17 +134,90 @@ impl WasmBoxState {
     pub fn snapshot(&self) -> WasmBoxStateSnapshot {
         WasmBoxStateSnapshot {
             time: self.time.load(Ordering::Relaxed),
+            monotonic_time: self.monotonic_time.load(Ordering::Relaxed),
             rng: self.rng.inner_rng.lock().expect(MUTEX_ERROR).clone(),
+            requests: self.requests.clone(),
+            timers: self.timers.clone(),
+            exit_value: self.exit_value.clone(),
         }
     }
 
-    pub fn load_snapshot(&self, snapshot: &WasmBoxStateSnapshot) {
+    pub fn load_snapshot(&mut self, snapshot: &WasmBoxStateSnapshot) {
         self.time.store(snapshot.time, Ordering::Relaxed);
+        self.monotonic_time
+            .store(snapshot.monotonic_time, Ordering::Relaxed);
         *self.rng.inner_rng.lock().expect(MUTEX_ERROR) = snapshot.rng.clone();
+        self.requests = snapshot.requests.clone();
+        self.timers = snapshot.timers.clone();
+        self.exit_value = snapshot.exit_value.clone();
+    }
+
+    pub fn requests(&self) -> &[HostRequest] {
+        &self.requests
+    }
+
+    pub fn add_requests(&mut self, requests: Vec<HostRequest>) {
+        self.requests.extend(requests);
+    }
+
+    pub fn has_request(&self, id: u32) -> bool {
+        self.requests.iter().any(|request| request.id() == id)
+    }
+
+    pub fn remove_request(&mut self, id: u32) {
+        self.requests.retain(|request| request.id() != id);
+    }
+
+    pub fn add_timers(&mut self, deadlines: Vec<u64>) {
+        for deadline in deadlines {
+            *self.timers.entry(deadline).or_default() += 1;
+        }
+    }
+
+    /// Remove one timer for each deadline, ignoring deadlines without one.
+    pub fn cancel_timers(&mut self, deadlines: Vec<u64>) {
+        for deadline in deadlines {
+            if let Some(count) = self.timers.get_mut(&deadline) {
+                *count -= 1;
+                if *count == 0 {
+                    self.timers.remove(&deadline);
+                }
+            }
+        }
+    }
+
+    pub fn next_timer_deadline(&self) -> Option<u64> {
+        self.timers.keys().next().copied()
+    }
+
+    pub fn has_due_timer(&self, time: u64) -> bool {
+        self.next_timer_deadline()
+            .is_some_and(|deadline| deadline <= time)
+    }
+
+    pub fn remove_due_timers(&mut self, time: u64) {
+        self.timers.retain(|&deadline, _| deadline > time);
+    }
+
+    pub fn exit_value(&self) -> Option<&[u8]> {
+        self.exit_value.as_deref()
+    }
+
+    /// Record that the guest has finished. A finished guest will never respond to requests
+    /// or timers, so they are dropped.
+    pub fn set_exit_value(&mut self, exit_value: Vec<u8>) {
+        self.exit_value = Some(exit_value);
+        self.requests.clear();
+        self.timers.clear();
+    }
+
+    pub fn rng_seed(&self) -> [u8; 32] {
+        self.rng.inner_rng.lock().expect(MUTEX_ERROR).get_seed()
     }
 
     pub fn set_time(&mut self, time: u64) {
         self.time.store(time, Ordering::Relaxed);
+        self.monotonic_time.fetch_max(time, Ordering::Relaxed);
     }
 }
 
@@ -128,3 +244,29 @@ impl WasiSystemClock for FakeSystemClock {
             .expect("Error creating time.")
     }
 }
+
+/// A monotonic clock which reads as the latest time set by the host, in milliseconds since
+/// the clock's creation time, so that it is deterministic and survives snapshots.
+pub struct FakeMonotonicClock {
+    time: Arc<AtomicU64>,
+}
+
+impl FakeMonotonicClock {
+    pub fn new(time: Arc<AtomicU64>) -> Self {
+        FakeMonotonicClock { time }
+    }
+}
+
+impl WasiMonotonicClock for FakeMonotonicClock {
+    fn resolution(&self) -> std::time::Duration {
+        Duration::from_millis(1)
+    }
+
+    fn now(&self, _precision: std::time::Duration) -> Instant {
+        let time = self.time.load(Ordering::Relaxed);
+
+        monotonic_anchor()
+            .checked_add(Duration::from_millis(time))
+            .expect("Error creating time.")
+    }
+}
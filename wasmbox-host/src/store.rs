@@ -0,0 +1,70 @@
+use crate::{HostFunctions, HostRequest, WasmBoxError};
+use wasmtime::ResourceLimiter;
+use wasmtime_wasi::WasiCtx;
+
+/// Data owned by the wasmtime store of a `WasmBoxHost`.
+pub struct StoreData<Output> {
+    pub wasi: WasiCtx,
+    /// Receives the guest's output. If `None`, output is queued in the host instead.
+    pub callback: Option<Box<dyn Fn(Output) + Send + Sync>>,
+    /// Whether output is passed to the callback as soon as the guest sends it. Otherwise it
+    /// is kept in `outbox`, because it may have to be discarded or returned by `call`.
+    pub stream: bool,
+    /// Output sent by the guest during the current call, which is passed to the callback (or
+    /// returned by `call`, or queued) once the call returns.
+    pub outbox: Vec<Output>,
+    /// Requests made by the guest during the current call, which become outstanding once the
+    /// call returns.
+    pub requests: Vec<HostRequest>,
+    /// Ids of the requests which were outstanding when the current call started, which the
+    /// guest may not reuse until they are responded to.
+    pub outstanding_request_ids: Vec<u32>,
+    /// Ids of the requests the guest cancelled during the current call, whose responses it no
+    /// longer wants.
+    pub cancelled_requests: Vec<u32>,
+    /// Deadlines of timers set by the guest during the current call.
+    pub timers: Vec<u64>,
+    /// Deadlines of timers the guest cancelled during the current call, including those which
+    /// fired while it was handling a message.
+    pub cancelled_timers: Vec<u64>,
+    /// The serialized value the guest finished with, if it finished during the current call.
+    pub exit_value: Option<Vec<u8>>,
+    pub limiter: GuestLimiter,
+    /// Set by a host function when it makes the guest trap, so that the cause of the trap can
+    /// be reported instead of the trap itself.
+    pub host_error: Option<WasmBoxError>,
+    pub host_functions: HostFunctions,
+}
+
+/// Enforces the memory and table limits of a box, and records when it refuses to let the
+/// guest grow, so that the resulting guest failure can be reported as such.
+#[derive(Default)]
+pub struct GuestLimiter {
+    pub max_memory_bytes: Option<usize>,
+    pub max_table_elements: Option<u32>,
+    /// Whether the most recent attempt to grow memory was refused.
+    pub memory_denied: bool,
+    /// Whether the most recent attempt to grow a table was refused.
+    pub table_denied: bool,
+}
+
+impl GuestLimiter {
+    pub fn reset(&mut self) {
+        self.memory_denied = false;
+        self.table_denied = false;
+    }
+}
+
+impl ResourceLimiter for GuestLimiter {
+    fn memory_growing(&mut self, _current: usize, desired: usize, _maximum: Option<usize>) -> bool {
+        let allowed = self.max_memory_bytes.is_none_or(|max| desired <= max);
+        self.memory_denied = !allowed;
+        allowed
+    }
+
+    fn table_growing(&mut self, _current: u32, desired: u32, _maximum: Option<u32>) -> bool {
+        let allowed = self.max_table_elements.is_none_or(|max| desired <= max);
+        self.table_denied = !allowed;
+        allowed
+    }
+}
@@ -0,0 +1,133 @@
+//! Checks that a box's behavior is reproducible, by replaying a journal on two hosts and
+//! comparing their state after every event with each other, and with the state recorded in
+//! the journal if it has one.
+
+use crate::journal::{self, JournalEvent, JournalReader};
+use crate::snapshot::hex;
+use crate::{Snapshot, WasmBoxError, WasmBoxHost, WasmBoxModule, WasmBoxOptions};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt::Display;
+use std::io::{BufReader, Read};
+
+/// The first point at which two replays of a journal reached different states, or reached the
+/// same state but not the one recorded in the journal.
+#[derive(Clone, Debug)]
+pub struct Divergence {
+    /// Number of journal events applied when the states differed. Zero means they differed
+    /// as soon as the initial snapshot was restored.
+    pub step: usize,
+    /// The last event applied, as described by `JournalEvent::describe`.
+    pub event: String,
+    /// Hashes of the two states, as returned by `WasmBoxHost::state_hash`.
+    pub hashes: ([u8; 32], [u8; 32]),
+    /// Hash of the state recorded in the journal at this point, if the journal records them
+    /// (see `JournalOptions::state_hashes`).
+    pub recorded: Option<[u8; 32]>,
+}
+
+impl Divergence {
+    /// Whether the replays agreed with each other, and only differed from the recording.
+    pub fn replays_agree(&self) -> bool {
+        self.hashes.0 == self.hashes.1
+    }
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.recorded {
+            Some(recorded) if self.replays_agree() => write!(
+                f,
+                "Replay diverged from the recording after event {} ({}): replayed {}, recorded {}",
+                self.step,
+                self.event,
+                hex(&self.hashes.0),
+                hex(&recorded)
+            ),
+            _ => write!(
+                f,
+                "State diverged after event {} ({}): {} != {}",
+                self.step,
+                self.event,
+                hex(&self.hashes.0),
+                hex(&self.hashes.1)
+            ),
+        }
+    }
+}
+
+/// The result of `verify_journal`.
+#[derive(Clone, Debug)]
+pub struct Verification {
+    /// Number of journal events replayed.
+    pub steps: usize,
+    /// Where the replays diverged, or `None` if they agreed after every event.
+    pub divergence: Option<Divergence>,
+}
+
+/// Replay a journal recorded with `WasmBoxHost::set_journal` on two hosts, both restored from
+/// `snapshot`, and compare the hashes of their state (see `WasmBoxHost::state_hash`) after
+/// every event. If the journal records state hashes, the replays are also compared with the
+/// recorded run, which catches a replay that is reproducible but not faithful, such as one
+/// where host functions respond differently than when the journal was recorded. Replay stops
+/// at the first divergence.
+///
+/// Output from the guests is discarded. Journal and snapshot errors are returned; errors from
+/// the guest while handling a replayed event are not, since both hosts should reproduce them.
+pub fn verify_journal<Input, Output>(
+    module: &WasmBoxModule,
+    snapshot: &Snapshot,
+    journal: impl Read,
+    options: WasmBoxOptions,
+) -> Result<Verification, WasmBoxError>
+where
+    Input: Serialize + DeserializeOwned,
+    Output: DeserializeOwned,
+{
+    let mut journal = BufReader::new(journal);
+    let mut recorded = journal::read_header(&mut journal, &module.hash)?;
+    let mut journal = JournalReader::new(journal);
+
+    // As when replaying, events which timed out are reproduced rather than run again.
+    let options = WasmBoxOptions {
+        message_deadline: None,
+        ..options
+    };
+    let mut hosts: [WasmBoxHost<Input, Output>; 2] = [
+        WasmBoxHost::from_snapshot_with_output_queue(module, snapshot, options.clone())?,
+        WasmBoxHost::from_snapshot_with_output_queue(module, snapshot, options)?,
+    ];
+
+    let mut steps = 0;
+    let mut event_description = "initial snapshot".to_string();
+    loop {
+        let hashes = (hosts[0].state_hash()?, hosts[1].state_hash()?);
+        if hashes.0 != hashes.1 || recorded.is_some_and(|recorded| recorded != hashes.0) {
+            return Ok(Verification {
+                steps,
+                divergence: Some(Divergence {
+                    step: steps,
+                    event: event_description,
+                    hashes,
+                    recorded,
+                }),
+            });
+        }
+
+        let (event, outcome): (JournalEvent<Input>, _) = match journal.next_event()? {
+            Some(event) => event,
+            None => break,
+        };
+        for host in &mut hosts {
+            host.apply_event(&event, outcome)?;
+            host.drain_outputs().for_each(drop);
+        }
+        steps += 1;
+        event_description = event.describe();
+        recorded = outcome.and_then(|outcome| outcome.state_hash);
+    }
+
+    Ok(Verification {
+        steps,
+        divergence: None,
+    })
+}
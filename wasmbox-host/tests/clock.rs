@@ -0,0 +1,27 @@
+mod common;
+
+use common::{load, module};
+use wasmbox_host::{WasmBoxHost, WasmBoxOptions};
+
+const CLOCK: &str = "clock";
+
+#[test]
+fn monotonic_clock_follows_host_time() {
+    let module = module(CLOCK);
+    let mut host: WasmBoxHost<u32, u64> = load(&module, WasmBoxOptions::default());
+
+    assert_eq!(vec![0], host.call(&0).unwrap());
+    host.set_time(1_000).unwrap();
+    assert_eq!(vec![1_000_000_000], host.call(&0).unwrap());
+
+    // The monotonic clock doesn't go backwards when the host's time does.
+    host.set_time(500).unwrap();
+    assert_eq!(vec![1_000_000_000], host.call(&0).unwrap());
+    host.set_time(2_500).unwrap();
+    assert_eq!(vec![2_500_000_000], host.call(&0).unwrap());
+
+    let snapshot = host.snapshot_state().unwrap();
+    let mut restored: WasmBoxHost<u32, u64> = load(&module, WasmBoxOptions::default());
+    restored.restore_snapshot(&snapshot).unwrap();
+    assert_eq!(vec![2_500_000_000], restored.call(&0).unwrap());
+}
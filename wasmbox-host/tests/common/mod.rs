@@ -0,0 +1,76 @@
+//! Helpers shared by the integration tests. Each test uses only some of them.
+#![allow(dead_code)]
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+use wasmbox_host::{WasmBoxHost, WasmBoxModule, WasmBoxOptions};
+
+/// The bump allocator shared by the hand-written modules in `tests/modules`, which never frees.
+/// It replaces the line `;; @allocator` in a module.
+const ALLOCATOR: &str = r#"  (global $heap (mut i32) (i32.const 1024))
+
+  (func (export "wasmbox_malloc") (param $size i32) (result i32)
+    (local $ptr i32)
+    (local.set $ptr (global.get $heap))
+    (global.set $heap (i32.add (global.get $heap) (local.get $size)))
+    (local.get $ptr))
+
+  (func (export "wasmbox_free") (param i32 i32))
+"#;
+
+/// Output collected by the callback of a host from `load_with_outputs`.
+pub type Outputs<Output> = Arc<Mutex<Vec<Output>>>;
+
+/// The path of the module `tests/modules/<name>.wat`, with the allocator filled in. The
+/// module is written out once per test binary.
+pub fn module_path(name: &str) -> String {
+    static PATHS: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+    let mut paths = PATHS.get_or_init(Mutex::default).lock().unwrap();
+    if let Some(path) = paths.get(name) {
+        return path.clone();
+    }
+
+    let source = format!("{}/tests/modules/{}.wat", env!("CARGO_MANIFEST_DIR"), name);
+    let source = std::fs::read_to_string(source).unwrap();
+    let source = source.replace("  ;; @allocator\n", ALLOCATOR);
+
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("modules");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(format!("{}.wat", name));
+    std::fs::write(&path, source).unwrap();
+
+    let path = path.to_str().unwrap().to_string();
+    paths.insert(name.to_string(), path.clone());
+    path
+}
+
+pub fn module(name: &str) -> WasmBoxModule {
+    WasmBoxModule::from_wasm_file(&module_path(name)).unwrap()
+}
+
+/// A host whose output is queued.
+pub fn load<Input: Serialize, Output: DeserializeOwned>(
+    module: &WasmBoxModule,
+    options: WasmBoxOptions,
+) -> WasmBoxHost<Input, Output> {
+    WasmBoxHost::new_with_output_queue(module, options).unwrap()
+}
+
+/// A host whose output is collected by its callback.
+pub fn load_with_outputs<Input: Serialize, Output: DeserializeOwned + Send + 'static>(
+    module: &WasmBoxModule,
+    options: WasmBoxOptions,
+) -> (WasmBoxHost<Input, Output>, Outputs<Output>) {
+    let outputs = Outputs::default();
+    let host = {
+        let outputs = outputs.clone();
+        WasmBoxHost::new_with_options(module, options, move |value| {
+            outputs.lock().unwrap().push(value)
+        })
+        .unwrap()
+    };
+
+    (host, outputs)
+}
@@ -0,0 +1,97 @@
+mod common;
+
+use common::{load_with_outputs, module, module_path};
+use wasmbox_host::{WasmBoxError, WasmBoxHost, WasmBoxModule, WasmBoxOptions};
+
+const GROW: &str = "grow";
+const GROW_OR_ABORT: &str = "grow_or_abort";
+const EMPTY: &str = "empty";
+const RESERVED_EXPORT: &str = "reserved_export";
+
+#[test]
+fn trap_poisons_box_until_restored() {
+    let module = module(GROW_OR_ABORT);
+    let mut host: WasmBoxHost<u32, u32> = WasmBoxHost::new(&module, |_| ()).unwrap();
+
+    host.message(&1).unwrap();
+    let snapshot = host.snapshot_state().unwrap();
+
+    // The module allows at most 4 pages, so the guest traps.
+    match host.message(&8) {
+        Err(WasmBoxError::Trap(trap)) => assert!(trap.trace().is_some()),
+        other => panic!("Expected a trap, got {:?}", other),
+    }
+    assert!(host.is_poisoned());
+    assert!(matches!(host.message(&0), Err(WasmBoxError::Poisoned)));
+
+    host.restore_snapshot(&snapshot).unwrap();
+    assert!(!host.is_poisoned());
+    host.message(&0).unwrap();
+}
+
+#[test]
+fn undecodable_output_is_a_serialization_error() {
+    let module = module(GROW);
+    // The guest sends 4-byte values, which are too short to decode as a u64.
+    let mut host: WasmBoxHost<u32, u64> = WasmBoxHost::from_snapshot(
+        &module,
+        &WasmBoxHost::<u32, u32>::new(&module, |_| ())
+            .unwrap()
+            .snapshot_state()
+            .unwrap(),
+        |_| (),
+    )
+    .unwrap();
+
+    assert!(matches!(
+        host.message(&0),
+        Err(WasmBoxError::Serialization(_))
+    ));
+    assert!(host.is_poisoned());
+}
+
+#[test]
+fn transactional_box_rolls_back_trap() {
+    let module = module(GROW_OR_ABORT);
+    let options = WasmBoxOptions {
+        transactional: true,
+        ..WasmBoxOptions::default()
+    };
+    let (mut host, outputs): (WasmBoxHost<u32, u32>, _) = load_with_outputs(&module, options);
+
+    host.message(&1).unwrap();
+    assert!(matches!(host.message(&8), Err(WasmBoxError::Trap(_))));
+    assert!(!host.is_poisoned());
+
+    // The box continues from its state before the trap: one page was added, and the last
+    // word of memory is still marked by it.
+    host.message(&2).unwrap();
+    host.message(&0).unwrap();
+    assert_eq!(vec![1, 2, 2], *outputs.lock().unwrap());
+
+    // The checkpoint is brought up to date with the grown memory before the next message.
+    assert!(matches!(host.message(&1), Err(WasmBoxError::Trap(_))));
+    host.message(&0).unwrap();
+    assert_eq!(vec![1, 2, 2, 2], *outputs.lock().unwrap());
+}
+
+#[test]
+fn missing_export() {
+    let module = module(EMPTY);
+    let result: Result<WasmBoxHost<u32, u32>, _> = WasmBoxHost::new(&module, |_| ());
+
+    match result {
+        Err(WasmBoxError::MissingExport(name)) => assert_eq!("wasmbox_malloc", name),
+        Err(other) => panic!("Expected a missing export, got {:?}", other),
+        Ok(_) => panic!("Expected a missing export."),
+    }
+}
+
+#[test]
+fn reserved_export_name() {
+    match WasmBoxModule::from_wasm_file(&module_path(RESERVED_EXPORT)) {
+        Err(WasmBoxError::ReservedExport(name)) => assert_eq!("wasmbox_global_0", name),
+        Err(other) => panic!("Expected a reserved export, got {:?}", other),
+        Ok(_) => panic!("Expected a reserved export."),
+    }
+}
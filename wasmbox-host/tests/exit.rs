@@ -0,0 +1,35 @@
+mod common;
+
+use common::{load, module};
+use wasmbox_host::{WasmBoxError, WasmBoxHost, WasmBoxOptions};
+
+const EXIT: &str = "exit";
+
+#[test]
+fn finished_box_refuses_messages() {
+    let module = module(EXIT);
+    let mut host: WasmBoxHost<u32, u32> = load(&module, WasmBoxOptions::default());
+
+    assert_eq!(vec![5], host.call(&5).unwrap());
+    assert!(!host.is_finished());
+    assert_eq!(None, host.exit_value::<u32>().unwrap());
+
+    assert!(host.call(&0).unwrap().is_empty());
+    assert!(host.is_finished());
+    assert_eq!(Some(42), host.exit_value::<u32>().unwrap());
+    assert!(matches!(host.message(&1), Err(WasmBoxError::BoxFinished)));
+    assert!(matches!(
+        host.respond(0, &1u32),
+        Err(WasmBoxError::BoxFinished)
+    ));
+
+    // Finishing is part of the box's state.
+    let snapshot = host.snapshot_state().unwrap();
+    let mut restored: WasmBoxHost<u32, u32> = load(&module, WasmBoxOptions::default());
+    restored.restore_snapshot(&snapshot).unwrap();
+    assert_eq!(Some(42), restored.exit_value::<u32>().unwrap());
+    assert!(matches!(
+        restored.message(&1),
+        Err(WasmBoxError::BoxFinished)
+    ));
+}
@@ -0,0 +1,34 @@
+mod common;
+
+use common::module;
+use wasmbox_host::{HostFunctions, WasmBoxError, WasmBoxHost, WasmBoxOptions};
+
+const HOST_FN: &str = "host_fn";
+
+#[test]
+fn guest_calls_host_function() {
+    let module = module(HOST_FN);
+    let mut host: WasmBoxHost<u32, u32> = WasmBoxHost::new(&module, |_| ()).unwrap();
+    host.register_host_fn("double", |value: u32| value * 2);
+
+    assert_eq!(vec![42], host.call(&21).unwrap());
+    assert_eq!(vec![8], host.call(&4).unwrap());
+}
+
+#[test]
+fn host_functions_from_options() {
+    let module = module(HOST_FN);
+    let mut host_functions = HostFunctions::default();
+    host_functions.register("double", |value: u32| value + value);
+    let options = WasmBoxOptions {
+        host_functions,
+        transactional: true,
+        ..WasmBoxOptions::default()
+    };
+    let mut host: WasmBoxHost<u32, u32> =
+        WasmBoxHost::new_with_options(&module, options, |_| ()).unwrap();
+
+    // Calling an unknown function fails, but the transactional box carries on.
+    assert!(matches!(host.call(&0), Err(WasmBoxError::Abi(_))));
+    assert_eq!(vec![6], host.call(&3).unwrap());
+}
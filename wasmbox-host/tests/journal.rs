@@ -0,0 +1,224 @@
+mod common;
+
+use common::{load, module};
+use std::io::Write;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use wasmbox_host::{
+    verify_journal, HostFunctions, JournalOptions, WasmBoxError, WasmBoxHost, WasmBoxOptions,
+};
+
+const ACCUMULATOR: &str = "accumulator";
+const CLOCK: &str = "clock";
+const HOST_FN: &str = "host_fn";
+const SPIN: &str = "spin";
+
+/// A journal sink whose contents can be read back while the host still holds it.
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn replay_rebuilds_box() {
+    let module = module(CLOCK);
+    let mut original: WasmBoxHost<u32, u64> = load(&module, WasmBoxOptions::default());
+    let initial = original.snapshot_state().unwrap();
+    let journal = SharedBuffer::default();
+    original.set_journal(journal.clone()).unwrap();
+
+    original.set_time(1_000).unwrap();
+    original.message(&0).unwrap();
+    let checkpoint = original.snapshot_state().unwrap();
+    original.set_time(3_000).unwrap();
+    original.restore_snapshot(&checkpoint).unwrap();
+    original.set_time(2_000).unwrap();
+    assert_eq!(vec![2_000_000_000], original.call(&0).unwrap());
+    original.drain_outputs().for_each(drop);
+
+    let mut replayed =
+        WasmBoxHost::from_snapshot_with_output_queue(&module, &initial, WasmBoxOptions::default())
+            .unwrap();
+    replayed
+        .replay_journal(journal.0.lock().unwrap().as_slice())
+        .unwrap();
+    assert_eq!(
+        vec![1_000_000_000, 2_000_000_000],
+        replayed.drain_outputs().collect::<Vec<_>>()
+    );
+
+    original.set_time(4_000).unwrap();
+    replayed.set_time(4_000).unwrap();
+    assert_eq!(original.call(&0).unwrap(), replayed.call(&0).unwrap());
+
+    let verification = verify_journal::<u32, u64>(
+        &module,
+        &initial,
+        journal.0.lock().unwrap().as_slice(),
+        WasmBoxOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(8, verification.steps);
+    assert!(verification.divergence.is_none());
+}
+
+#[test]
+fn replay_reproduces_timeouts() {
+    let module = module(SPIN);
+    let options = WasmBoxOptions {
+        message_deadline: Some(Duration::from_millis(50)),
+        ..WasmBoxOptions::default()
+    };
+    let mut original: WasmBoxHost<u32, u32> = load(&module, options.clone());
+    let initial = original.snapshot_state().unwrap();
+    let journal = SharedBuffer::default();
+    original.set_journal(journal.clone()).unwrap();
+
+    original.message(&0).unwrap();
+    assert!(matches!(original.message(&1), Err(WasmBoxError::Timeout)));
+    original.message(&0).unwrap();
+
+    // The guest spins forever on the message which timed out, so replaying it would hang
+    // without the deadline. Replay reproduces the timeout instead.
+    let mut replayed: WasmBoxHost<u32, u32> =
+        WasmBoxHost::from_snapshot_with_output_queue(&module, &initial, WasmBoxOptions::default())
+            .unwrap();
+    replayed
+        .replay_journal(journal.0.lock().unwrap().as_slice())
+        .unwrap();
+    assert_eq!(vec![1, 2], replayed.drain_outputs().collect::<Vec<_>>());
+    assert_eq!(
+        original.state_hash().unwrap(),
+        replayed.state_hash().unwrap()
+    );
+
+    let verification = verify_journal::<u32, u32>(
+        &module,
+        &initial,
+        journal.0.lock().unwrap().as_slice(),
+        options,
+    )
+    .unwrap();
+    assert_eq!(3, verification.steps);
+    assert!(verification.divergence.is_none());
+}
+
+#[test]
+fn refuse_journal_from_other_module() {
+    let module = module(CLOCK);
+    let mut original: WasmBoxHost<u32, u64> = load(&module, WasmBoxOptions::default());
+    let journal = SharedBuffer::default();
+    original.set_journal(journal.clone()).unwrap();
+    original.message(&0).unwrap();
+
+    let other = common::module(ACCUMULATOR);
+    let mut other: WasmBoxHost<u32, u32> = load(&other, WasmBoxOptions::default());
+    let error = other
+        .replay_journal(journal.0.lock().unwrap().as_slice())
+        .unwrap_err();
+    assert!(matches!(error, WasmBoxError::InvalidJournal(_)));
+}
+
+#[test]
+fn verify_finds_nondeterministic_host_function() {
+    let module = module(HOST_FN);
+    let calls = Arc::new(AtomicU32::new(0));
+    let mut host_functions = HostFunctions::default();
+    {
+        let calls = calls.clone();
+        host_functions.register("double", move |value: u32| {
+            // Returns the same for the first three calls, and differently for every call after.
+            value * 2 + calls.fetch_add(1, Ordering::SeqCst).saturating_sub(2)
+        });
+    }
+    let options = WasmBoxOptions {
+        host_functions,
+        ..WasmBoxOptions::default()
+    };
+
+    let mut host: WasmBoxHost<u32, u32> = load(&module, options.clone());
+    let initial = host.snapshot_state().unwrap();
+    let journal = SharedBuffer::default();
+    host.set_journal(journal.clone()).unwrap();
+    host.set_time(1_000).unwrap();
+    for value in [1, 2, 3] {
+        host.message(&value).unwrap();
+    }
+
+    // The two replays each call the host function once per message.
+    calls.store(0, Ordering::SeqCst);
+    let verification = verify_journal::<u32, u32>(
+        &module,
+        &initial,
+        journal.0.lock().unwrap().as_slice(),
+        options,
+    )
+    .unwrap();
+    let divergence = verification.divergence.unwrap();
+    assert_eq!(3, divergence.step);
+    assert_eq!("message", divergence.event);
+}
+
+#[test]
+fn verify_compares_replays_with_recording() {
+    let module = module(HOST_FN);
+    let options_multiplying_by = |factor: u32| {
+        let mut host_functions = HostFunctions::default();
+        host_functions.register("double", move |value: u32| value * factor);
+        WasmBoxOptions {
+            host_functions,
+            ..WasmBoxOptions::default()
+        }
+    };
+
+    let mut host: WasmBoxHost<u32, u32> = load(&module, options_multiplying_by(2));
+    let initial = host.snapshot_state().unwrap();
+    let journal = SharedBuffer::default();
+    host.set_journal_with_options(journal.clone(), JournalOptions { state_hashes: true })
+        .unwrap();
+    host.set_time(1_000).unwrap();
+    for value in [1, 2, 3] {
+        host.message(&value).unwrap();
+    }
+    let later = host.snapshot_state().unwrap();
+
+    let verify = |snapshot, options| {
+        verify_journal::<u32, u32>(
+            &module,
+            snapshot,
+            journal.0.lock().unwrap().as_slice(),
+            options,
+        )
+        .unwrap()
+    };
+
+    let verification = verify(&initial, options_multiplying_by(2));
+    assert_eq!(4, verification.steps);
+    assert!(verification.divergence.is_none());
+
+    // Both replays get the same, wrong, response from the host function, so they agree with
+    // each other but not with the recording.
+    let divergence = verify(&initial, options_multiplying_by(3))
+        .divergence
+        .unwrap();
+    assert_eq!(2, divergence.step);
+    assert_eq!("message", divergence.event);
+    assert!(divergence.replays_agree());
+    assert!(divergence.recorded.is_some());
+
+    // Replaying from a different snapshot than the journal started from.
+    let divergence = verify(&later, options_multiplying_by(2))
+        .divergence
+        .unwrap();
+    assert_eq!(0, divergence.step);
+    assert!(divergence.replays_agree());
+}
@@ -0,0 +1,108 @@
+mod common;
+
+use common::{load, load_with_outputs, module};
+use std::time::{Duration, Instant};
+use wasmbox_host::{WasmBoxError, WasmBoxHost, WasmBoxOptions};
+
+const SPIN: &str = "spin";
+const GROW: &str = "grow";
+const GROW_OR_ABORT: &str = "grow_or_abort";
+const START: &str = "start";
+
+#[test]
+fn message_runs_out_of_fuel() {
+    let mut host: WasmBoxHost<u32, u32> = load(
+        &module(SPIN),
+        WasmBoxOptions {
+            message_fuel: Some(10_000),
+            ..WasmBoxOptions::default()
+        },
+    );
+
+    host.message(&0).unwrap();
+    let fuel = host.last_fuel_consumed();
+    assert!(fuel > 0 && fuel < 10_000);
+
+    assert!(matches!(host.message(&1), Err(WasmBoxError::OutOfFuel)));
+    assert!(host.last_fuel_consumed() > fuel);
+
+    // Fuel is replenished for the next message.
+    host.message(&0).unwrap();
+    assert_eq!(fuel, host.last_fuel_consumed());
+}
+
+#[test]
+fn message_times_out_and_rolls_back() {
+    let (mut host, outputs): (WasmBoxHost<u32, u32>, _) = load_with_outputs(
+        &module(SPIN),
+        WasmBoxOptions {
+            message_deadline: Some(Duration::from_millis(50)),
+            ..WasmBoxOptions::default()
+        },
+    );
+
+    host.message(&0).unwrap();
+
+    let start = Instant::now();
+    assert!(matches!(host.message(&1), Err(WasmBoxError::Timeout)));
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    // The message that timed out is not counted.
+    host.message(&0).unwrap();
+    assert_eq!(vec![1, 2], *outputs.lock().unwrap());
+}
+
+#[test]
+fn memory_limit_exceeded() {
+    let module = module(GROW_OR_ABORT);
+    let options = WasmBoxOptions {
+        max_memory_bytes: Some(2 * 0x10000),
+        ..WasmBoxOptions::default()
+    };
+    let mut host: WasmBoxHost<u32, u32> = load(&module, options);
+
+    host.message(&1).unwrap();
+    assert!(matches!(
+        host.message(&1),
+        Err(WasmBoxError::MemoryLimitExceeded)
+    ));
+}
+
+#[test]
+fn guest_may_carry_on_after_memory_is_refused() {
+    let module = module(GROW);
+    let options = WasmBoxOptions {
+        max_memory_bytes: Some(2 * 0x10000),
+        ..WasmBoxOptions::default()
+    };
+    let (mut host, outputs): (WasmBoxHost<u32, u32>, _) = load_with_outputs(&module, options);
+
+    // The guest ignores the refusal and marks the last word of the memory it has.
+    host.message(&2).unwrap();
+    host.message(&1).unwrap();
+    assert_eq!(vec![2, 1], *outputs.lock().unwrap());
+}
+
+#[test]
+fn start_function_is_metered_like_initialization() {
+    let module = module(START);
+    let options = WasmBoxOptions {
+        init_fuel: Some(100_000),
+        init_deadline: Some(Duration::from_secs(5)),
+        message_fuel: Some(10_000),
+        ..WasmBoxOptions::default()
+    };
+    let (mut host, outputs): (WasmBoxHost<u32, u32>, _) = load_with_outputs(&module, options);
+
+    host.message(&0).unwrap();
+    assert_eq!(vec![1000], *outputs.lock().unwrap());
+
+    let options = WasmBoxOptions {
+        init_fuel: Some(100),
+        ..WasmBoxOptions::default()
+    };
+    assert!(matches!(
+        WasmBoxHost::<u32, u32>::new_with_options(&module, options, |_| ()),
+        Err(WasmBoxError::OutOfFuel)
+    ));
+}
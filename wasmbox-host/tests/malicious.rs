@@ -0,0 +1,83 @@
+//! Hostile guest modules must not be able to crash the host; each of their attacks should
+//! surface as an error from `message`.
+
+mod common;
+
+use common::{load_with_outputs, module, Outputs};
+use wasmbox_host::{WasmBoxError, WasmBoxHost, WasmBoxOptions};
+
+const BAD_POINTERS: &str = "bad_pointers";
+const BAD_MALLOC: &str = "bad_malloc";
+
+fn load(name: &str, options: WasmBoxOptions) -> (WasmBoxHost<u32, u32>, Outputs<u32>) {
+    load_with_outputs(&module(name), options)
+}
+
+#[test]
+fn callback_out_of_bounds() {
+    let (mut host, outputs) = load(BAD_POINTERS, WasmBoxOptions::default());
+    let snapshot = host.snapshot_state().unwrap();
+
+    for attack in [1, 2] {
+        assert!(matches!(host.message(&attack), Err(WasmBoxError::Abi(_))));
+        assert!(host.is_poisoned());
+        host.restore_snapshot(&snapshot).unwrap();
+    }
+
+    host.message(&0).unwrap();
+    assert_eq!(vec![0], *outputs.lock().unwrap());
+}
+
+#[test]
+fn callback_message_too_large() {
+    let (mut host, outputs) = load(
+        BAD_POINTERS,
+        WasmBoxOptions {
+            max_message_bytes: Some(1024),
+            ..WasmBoxOptions::default()
+        },
+    );
+
+    assert!(matches!(
+        host.message(&3),
+        Err(WasmBoxError::MessageTooLarge {
+            len: 0x8000,
+            max: 1024
+        })
+    ));
+    assert!(outputs.lock().unwrap().is_empty());
+}
+
+#[test]
+fn input_message_too_large() {
+    let module = module(BAD_POINTERS);
+    let options = WasmBoxOptions {
+        max_message_bytes: Some(16),
+        ..WasmBoxOptions::default()
+    };
+    let mut host: WasmBoxHost<Vec<u32>, u32> =
+        WasmBoxHost::new_with_options(&module, options, |_| ()).unwrap();
+
+    assert!(matches!(
+        host.message(&vec![0; 16]),
+        Err(WasmBoxError::MessageTooLarge { .. })
+    ));
+    // Nothing reached the guest, so the box is still usable.
+    assert!(!host.is_poisoned());
+}
+
+#[test]
+fn malloc_returns_null() {
+    let (mut host, _) = load(BAD_MALLOC, WasmBoxOptions::default());
+
+    host.message(&0).unwrap();
+    assert!(matches!(host.message(&1), Err(WasmBoxError::Abi(_))));
+}
+
+#[test]
+fn malloc_returns_out_of_bounds() {
+    let (mut host, _) = load(BAD_MALLOC, WasmBoxOptions::default());
+
+    host.message(&0xffff_fffe).unwrap();
+    assert!(matches!(host.message(&1), Err(WasmBoxError::Abi(_))));
+}
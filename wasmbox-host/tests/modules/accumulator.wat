@@ -0,0 +1,20 @@
+;; A hand-written guest module which keeps a running total of the u32 messages it receives
+;; and sends the total back after each message.
+;;
+;; The total and the allocator's bump pointer are kept in non-exported globals rather than in
+;; linear memory, like the shadow stack pointer of a compiled Rust module.
+(module
+  (import "env" "wasmbox_callback" (func $callback (param i32 i32)))
+
+  (memory (export "memory") 1)
+
+  (global $total (mut i32) (i32.const 0))
+
+  (func (export "wasmbox_initialize"))
+
+  ;; @allocator
+
+  (func (export "wasmbox_send") (param $ptr i32) (param $len i32)
+    (global.set $total (i32.add (global.get $total) (i32.load (local.get $ptr))))
+    (i32.store (i32.const 0) (global.get $total))
+    (call $callback (i32.const 0) (i32.const 4))))
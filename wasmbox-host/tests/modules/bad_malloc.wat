@@ -0,0 +1,19 @@
+;; A hand-written hostile guest module whose allocator returns whatever address it was last
+;; sent as a u32 message, instead of allocating. It echoes each message back.
+(module
+  (import "env" "wasmbox_callback" (func $callback (param i32 i32)))
+
+  (memory (export "memory") 1)
+
+  (global $next (mut i32) (i32.const 1024))
+
+  (func (export "wasmbox_initialize"))
+
+  (func (export "wasmbox_malloc") (param $size i32) (result i32)
+    (global.get $next))
+
+  (func (export "wasmbox_free") (param i32 i32))
+
+  (func (export "wasmbox_send") (param $ptr i32) (param $len i32)
+    (global.set $next (i32.load (local.get $ptr)))
+    (call $callback (local.get $ptr) (local.get $len))))
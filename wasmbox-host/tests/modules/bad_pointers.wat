@@ -0,0 +1,29 @@
+;; A hand-written hostile guest module which passes bad pointers to the host. The u32 value
+;; of each message selects what it sends back:
+;;   0: the message itself, correctly.
+;;   1: a message which runs past the end of memory.
+;;   2: a message whose end overflows a u32.
+;;   3: a 32 KiB message of zeros, which is in bounds.
+(module
+  (import "env" "wasmbox_callback" (func $callback (param i32 i32)))
+
+  (memory (export "memory") 1)
+
+  (func (export "wasmbox_initialize"))
+
+  ;; @allocator
+
+  (func (export "wasmbox_send") (param $ptr i32) (param $len i32)
+    (block $done
+      (block $3
+        (block $2
+          (block $1
+            (block $0
+              (br_table $0 $1 $2 $3 $done (i32.load (local.get $ptr))))
+            (call $callback (local.get $ptr) (local.get $len))
+            (br $done))
+          (call $callback (i32.const 0xfff0) (i32.const 0x20))
+          (br $done))
+        (call $callback (i32.const 0xffffffff) (i32.const 0xffffffff))
+        (br $done))
+      (call $callback (i32.const 0x8000) (i32.const 0x8000)))))
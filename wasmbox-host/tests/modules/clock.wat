@@ -0,0 +1,18 @@
+;; A hand-written guest module which reads the WASI monotonic clock whenever it receives a
+;; message, and sends back the reading in nanoseconds as a u64.
+(module
+  (import "env" "wasmbox_callback" (func $callback (param i32 i32)))
+  (import "wasi_snapshot_preview1" "clock_time_get"
+    (func $clock_time_get (param i32 i64 i32) (result i32)))
+
+  (memory (export "memory") 1)
+
+  (func (export "wasmbox_initialize"))
+
+  ;; @allocator
+
+  (func (export "wasmbox_send") (param $ptr i32) (param $len i32)
+    ;; Clock 1 is the monotonic clock.
+    (if (call $clock_time_get (i32.const 1) (i64.const 1) (i32.const 8))
+      (then unreachable))
+    (call $callback (i32.const 8) (i32.const 8))))
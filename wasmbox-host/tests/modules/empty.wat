@@ -0,0 +1,3 @@
+;; A module which exports its memory but none of the functions of the wasmbox ABI.
+(module
+  (memory (export "memory") 1))
@@ -0,0 +1,17 @@
+;; A hand-written guest module which echoes each message it receives, until it receives zero,
+;; at which point it finishes with the exit value 42.
+(module
+  (import "env" "wasmbox_callback" (func $callback (param i32 i32)))
+  (import "env" "wasmbox_exit" (func $exit (param i32 i32)))
+
+  (memory (export "memory") 1)
+  (data (i32.const 8) "\2a\00\00\00")
+
+  (func (export "wasmbox_initialize"))
+
+  ;; @allocator
+
+  (func (export "wasmbox_send") (param $ptr i32) (param $len i32)
+    (if (i32.load (local.get $ptr))
+      (then (call $callback (local.get $ptr) (local.get $len)))
+      (else (call $exit (i32.const 8) (i32.const 4))))))
@@ -0,0 +1,24 @@
+;; A hand-written guest module which grows its memory by the number of pages given in each
+;; u32 message, and marks the last word of memory with that number when it does. After each
+;; message, it sends back the value of the last word of memory.
+(module
+  (import "env" "wasmbox_callback" (func $callback (param i32 i32)))
+
+  (memory (export "memory") 1 4)
+
+  (func (export "wasmbox_initialize"))
+
+  ;; @allocator
+
+  (func $last_word (result i32)
+    (i32.sub (i32.mul (memory.size) (i32.const 0x10000)) (i32.const 4)))
+
+  (func (export "wasmbox_send") (param $ptr i32) (param $len i32)
+    (local $pages i32)
+    (local.set $pages (i32.load (local.get $ptr)))
+    (if (local.get $pages)
+      (then
+        (drop (memory.grow (local.get $pages)))
+        (i32.store (call $last_word) (local.get $pages))))
+    (i32.store (i32.const 0) (i32.load (call $last_word)))
+    (call $callback (i32.const 0) (i32.const 4))))
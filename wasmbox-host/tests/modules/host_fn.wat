@@ -0,0 +1,26 @@
+;; A hand-written guest module which passes each message it receives as the request to the
+;; host function "double", or to "missing" if the message is zero, and sends the response
+;; back.
+(module
+  (import "env" "wasmbox_callback" (func $callback (param i32 i32)))
+  (import "env" "wasmbox_call_host" (func $call_host (param i32 i32 i32 i32) (result i64)))
+
+  (memory (export "memory") 1)
+  (data (i32.const 16) "doublemissing")
+
+  (func (export "wasmbox_initialize"))
+
+  ;; @allocator
+
+  (func (export "wasmbox_send") (param $ptr i32) (param $len i32)
+    (local $response i64)
+    (if (i32.load (local.get $ptr))
+      (then
+        (local.set $response
+          (call $call_host (i32.const 16) (i32.const 6) (local.get $ptr) (local.get $len))))
+      (else
+        (local.set $response
+          (call $call_host (i32.const 22) (i32.const 7) (local.get $ptr) (local.get $len)))))
+    (call $callback
+      (i32.wrap_i64 (i64.shr_u (local.get $response) (i64.const 32)))
+      (i32.wrap_i64 (local.get $response)))))
@@ -0,0 +1,22 @@
+;; Like accumulator.wat, a hand-written guest module which keeps a running total of the u32
+;; messages it receives and sends the total back after each message, but which also sends the
+;; total when it is initialized.
+(module
+  (import "env" "wasmbox_callback" (func $callback (param i32 i32)))
+
+  (memory (export "memory") 1)
+
+  (global $total (mut i32) (i32.const 0))
+
+  (func $send_total
+    (i32.store (i32.const 0) (global.get $total))
+    (call $callback (i32.const 0) (i32.const 4)))
+
+  (func (export "wasmbox_initialize")
+    (call $send_total))
+
+  ;; @allocator
+
+  (func (export "wasmbox_send") (param $ptr i32) (param $len i32)
+    (global.set $total (i32.add (global.get $total) (i32.load (local.get $ptr))))
+    (call $send_total)))
@@ -0,0 +1,16 @@
+;; A hand-written guest module which reads 8 bytes of WASI randomness whenever it receives a
+;; message, and sends them back as a u64.
+(module
+  (import "env" "wasmbox_callback" (func $callback (param i32 i32)))
+  (import "wasi_snapshot_preview1" "random_get" (func $random_get (param i32 i32) (result i32)))
+
+  (memory (export "memory") 1)
+
+  (func (export "wasmbox_initialize"))
+
+  ;; @allocator
+
+  (func (export "wasmbox_send") (param $ptr i32) (param $len i32)
+    (if (call $random_get (i32.const 8) (i32.const 8))
+      (then unreachable))
+    (call $callback (i32.const 8) (i32.const 8))))
@@ -0,0 +1,18 @@
+;; A hand-written guest module which, for each u32 message, makes a request to the host
+;; with that value as both its id and its payload. When the host responds, it sends the
+;; response back.
+(module
+  (import "env" "wasmbox_callback" (func $callback (param i32 i32)))
+  (import "env" "wasmbox_request" (func $request (param i32 i32 i32)))
+
+  (memory (export "memory") 1)
+
+  (func (export "wasmbox_initialize"))
+
+  ;; @allocator
+
+  (func (export "wasmbox_send") (param $ptr i32) (param $len i32)
+    (call $request (i32.load (local.get $ptr)) (local.get $ptr) (local.get $len)))
+
+  (func (export "wasmbox_respond") (param $id i32) (param $ptr i32) (param $len i32)
+    (call $callback (local.get $ptr) (local.get $len))))
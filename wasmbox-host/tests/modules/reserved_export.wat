@@ -0,0 +1,8 @@
+;; A module which exports a function under a name reserved for the exports that wasmbox adds
+;; to snapshot a module's globals.
+(module
+  (memory (export "memory") 1)
+
+  (global $count (mut i32) (i32.const 0))
+
+  (func (export "wasmbox_global_0")))
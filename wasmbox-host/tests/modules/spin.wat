@@ -0,0 +1,19 @@
+;; A hand-written guest module which counts the u32 messages it receives. Given a non-zero
+;; message, it spins forever after counting it; otherwise it sends back the count.
+(module
+  (import "env" "wasmbox_callback" (func $callback (param i32 i32)))
+
+  (memory (export "memory") 1)
+
+  (global $count (mut i32) (i32.const 0))
+
+  (func (export "wasmbox_initialize"))
+
+  ;; @allocator
+
+  (func (export "wasmbox_send") (param $ptr i32) (param $len i32)
+    (global.set $count (i32.add (global.get $count) (i32.const 1)))
+    (if (i32.load (local.get $ptr))
+      (then (loop $spin (br $spin))))
+    (i32.store (i32.const 0) (global.get $count))
+    (call $callback (i32.const 0) (i32.const 4))))
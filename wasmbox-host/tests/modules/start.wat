@@ -0,0 +1,23 @@
+;; A hand-written guest module with a start function, which counts to 1000 before the module
+;; is initialized. Every message sends back the count.
+(module
+  (import "env" "wasmbox_callback" (func $callback (param i32 i32)))
+
+  (memory (export "memory") 1)
+
+  (global $count (mut i32) (i32.const 0))
+
+  (func $start
+    (loop $count
+      (global.set $count (i32.add (global.get $count) (i32.const 1)))
+      (br_if $count (i32.lt_u (global.get $count) (i32.const 1000)))))
+
+  (start $start)
+
+  (func (export "wasmbox_initialize"))
+
+  ;; @allocator
+
+  (func (export "wasmbox_send") (param $ptr i32) (param $len i32)
+    (i32.store (i32.const 0) (global.get $count))
+    (call $callback (i32.const 0) (i32.const 4))))
@@ -0,0 +1,21 @@
+;; A hand-written guest module which sends back each message it receives, then passes it to
+;; the host function "observe" and sends back the response.
+(module
+  (import "env" "wasmbox_callback" (func $callback (param i32 i32)))
+  (import "env" "wasmbox_call_host" (func $call_host (param i32 i32 i32 i32) (result i64)))
+
+  (memory (export "memory") 1)
+  (data (i32.const 16) "observe")
+
+  (func (export "wasmbox_initialize"))
+
+  ;; @allocator
+
+  (func (export "wasmbox_send") (param $ptr i32) (param $len i32)
+    (local $response i64)
+    (call $callback (local.get $ptr) (local.get $len))
+    (local.set $response
+      (call $call_host (i32.const 16) (i32.const 7) (local.get $ptr) (local.get $len)))
+    (call $callback
+      (i32.wrap_i64 (i64.shr_u (local.get $response) (i64.const 32)))
+      (i32.wrap_i64 (local.get $response)))))
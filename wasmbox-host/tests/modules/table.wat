@@ -0,0 +1,49 @@
+;; A hand-written guest module with a table which it changes at runtime. Each u32 message
+;; is an operation on the table: 1 sets element 0 to $two, 2 grows the table by one element
+;; set to $three, 3 sets element 0 to null, and anything else leaves it alone. After each
+;; message, it sends back the table's size times 1000, plus the result of calling each of
+;; its first three elements times 1, 10 and 100, counting null elements as 0.
+(module
+  (import "env" "wasmbox_callback" (func $callback (param i32 i32)))
+
+  (memory (export "memory") 1)
+
+  (type $constant (func (result i32)))
+  (table $table 1 funcref)
+  (elem (i32.const 0) $one)
+  (elem declare func $two $three)
+
+  (func $one (result i32) (i32.const 1))
+  (func $two (result i32) (i32.const 2))
+  (func $three (result i32) (i32.const 3))
+
+  (func (export "wasmbox_initialize"))
+
+  ;; @allocator
+
+  (func $element (param $index i32) (result i32)
+    (if (i32.ge_u (local.get $index) (table.size $table))
+      (then (return (i32.const 0))))
+    (if (ref.is_null (table.get $table (local.get $index)))
+      (then (return (i32.const 0))))
+    (call_indirect $table (type $constant) (local.get $index)))
+
+  (func (export "wasmbox_send") (param $ptr i32) (param $len i32)
+    (local $op i32)
+    (local.set $op (i32.load (local.get $ptr)))
+    (if (i32.eq (local.get $op) (i32.const 1))
+      (then (table.set $table (i32.const 0) (ref.func $two))))
+    (if (i32.eq (local.get $op) (i32.const 2))
+      (then (drop (table.grow $table (ref.func $three) (i32.const 1)))))
+    (if (i32.eq (local.get $op) (i32.const 3))
+      (then (table.set $table (i32.const 0) (ref.null func))))
+
+    (i32.store (i32.const 0)
+      (i32.add
+        (i32.mul (table.size $table) (i32.const 1000))
+        (i32.add
+          (call $element (i32.const 0))
+          (i32.add
+            (i32.mul (call $element (i32.const 1)) (i32.const 10))
+            (i32.mul (call $element (i32.const 2)) (i32.const 100))))))
+    (call $callback (i32.const 0) (i32.const 4))))
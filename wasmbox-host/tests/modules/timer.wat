@@ -0,0 +1,21 @@
+;; A hand-written guest module which sets a timer for the deadline in each message it
+;; receives, and sends the number of times it has been woken whenever the host wakes it.
+(module
+  (import "env" "wasmbox_callback" (func $callback (param i32 i32)))
+  (import "env" "wasmbox_timer" (func $timer (param i64)))
+
+  (memory (export "memory") 1)
+
+  (global $wakes (mut i32) (i32.const 0))
+
+  (func (export "wasmbox_initialize"))
+
+  ;; @allocator
+
+  (func (export "wasmbox_send") (param $ptr i32) (param $len i32)
+    (call $timer (i64.load (local.get $ptr))))
+
+  (func (export "wasmbox_wake")
+    (global.set $wakes (i32.add (global.get $wakes) (i32.const 1)))
+    (i32.store (i32.const 8) (global.get $wakes))
+    (call $callback (i32.const 8) (i32.const 4))))
@@ -0,0 +1,13 @@
+;; A hand-written guest module which sets a timer for the deadline in each message it
+;; receives, but can't be woken for it.
+(module
+  (import "env" "wasmbox_timer" (func $timer (param i64)))
+
+  (memory (export "memory") 1)
+
+  (func (export "wasmbox_initialize"))
+
+  ;; @allocator
+
+  (func (export "wasmbox_send") (param $ptr i32) (param $len i32)
+    (call $timer (i64.load (local.get $ptr)))))
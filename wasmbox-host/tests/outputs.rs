@@ -0,0 +1,96 @@
+mod common;
+
+use common::{load, load_with_outputs, module, Outputs};
+use wasmbox_host::{HostFunctions, WasmBoxError, WasmBoxHost, WasmBoxOptions};
+
+const GROW_OR_ABORT: &str = "grow_or_abort";
+const INIT_OUTPUT: &str = "init_output";
+const STREAM: &str = "stream";
+
+/// Load `STREAM` with a host function which responds with the number of outputs the callback
+/// has received so far.
+fn load_stream(options: WasmBoxOptions) -> (WasmBoxHost<u32, u32>, Outputs<u32>) {
+    let module = module(STREAM);
+    let outputs = Outputs::default();
+    let mut host_functions = HostFunctions::default();
+    {
+        let outputs = outputs.clone();
+        host_functions.register("observe", move |_: u32| {
+            outputs.lock().unwrap().len() as u32
+        });
+    }
+    let options = WasmBoxOptions {
+        host_functions,
+        ..options
+    };
+    let host = {
+        let outputs = outputs.clone();
+        WasmBoxHost::new_with_options(&module, options, move |value| {
+            outputs.lock().unwrap().push(value)
+        })
+        .unwrap()
+    };
+
+    (host, outputs)
+}
+
+#[test]
+fn call_returns_outputs() {
+    let module = module(INIT_OUTPUT);
+    let (mut host, outputs): (WasmBoxHost<u32, u32>, _) =
+        load_with_outputs(&module, WasmBoxOptions::default());
+
+    assert_eq!(vec![3], host.call(&3).unwrap());
+    host.message(&4).unwrap();
+    assert_eq!(vec![12], host.call(&5).unwrap());
+
+    // Only output from `message` (and initialization) goes to the callback.
+    assert_eq!(vec![0, 7], *outputs.lock().unwrap());
+}
+
+#[test]
+fn call_reports_guest_failure() {
+    let module = module(GROW_OR_ABORT);
+    let mut host: WasmBoxHost<u32, u32> = WasmBoxHost::new(&module, |_| ()).unwrap();
+
+    assert_eq!(vec![1], host.call(&1).unwrap());
+    assert!(matches!(host.call(&8), Err(WasmBoxError::Trap(_))));
+    assert!(matches!(host.call(&0), Err(WasmBoxError::Poisoned)));
+}
+
+#[test]
+fn output_queue() {
+    let module = module(INIT_OUTPUT);
+    let mut host: WasmBoxHost<u32, u32> = load(&module, WasmBoxOptions::default());
+
+    host.message(&3).unwrap();
+    host.message(&4).unwrap();
+    assert_eq!(Some(0), host.next_output());
+    assert_eq!(vec![3, 7], host.drain_outputs().collect::<Vec<_>>());
+    assert_eq!(None, host.next_output());
+
+    // Output returned by `call` is not queued.
+    assert_eq!(vec![12], host.call(&5).unwrap());
+    host.message(&1).unwrap();
+    assert_eq!(vec![13], host.drain_outputs().collect::<Vec<_>>());
+}
+
+#[test]
+fn callback_receives_output_as_it_is_sent() {
+    let (mut host, outputs) = load_stream(WasmBoxOptions::default());
+    host.message(&7).unwrap();
+    assert_eq!(vec![7, 1], *outputs.lock().unwrap());
+
+    // Output from `call` is still returned rather than streamed.
+    assert_eq!(vec![8, 2], host.call(&8).unwrap());
+}
+
+#[test]
+fn output_of_message_which_may_roll_back_is_held() {
+    let (mut host, outputs) = load_stream(WasmBoxOptions {
+        transactional: true,
+        ..WasmBoxOptions::default()
+    });
+    host.message(&7).unwrap();
+    assert_eq!(vec![7, 0], *outputs.lock().unwrap());
+}
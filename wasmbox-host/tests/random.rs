@@ -0,0 +1,53 @@
+mod common;
+
+use common::module;
+use wasmbox_host::{derive_rng_seed, WasmBoxHost, WasmBoxModule, WasmBoxOptions};
+
+const RANDOM: &str = "random";
+
+fn load_seeded(module: &WasmBoxModule, rng_seed: Option<[u8; 32]>) -> WasmBoxHost<u32, u64> {
+    let options = WasmBoxOptions {
+        rng_seed,
+        ..WasmBoxOptions::default()
+    };
+    common::load(module, options)
+}
+
+fn draw(host: &mut WasmBoxHost<u32, u64>) -> u64 {
+    host.call(&0).unwrap()[0]
+}
+
+#[test]
+fn seeds_select_random_streams() {
+    let module = module(RANDOM);
+
+    let a = draw(&mut load_seeded(&module, None));
+    assert_eq!(a, draw(&mut load_seeded(&module, None)));
+
+    let seed_1 = derive_rng_seed(b"key", "box-1");
+    let seed_2 = derive_rng_seed(b"key", "box-2");
+    assert_ne!(seed_1, seed_2);
+    assert_ne!(seed_1, derive_rng_seed(b"other key", "box-1"));
+
+    let b = draw(&mut load_seeded(&module, Some(seed_1)));
+    let c = draw(&mut load_seeded(&module, Some(seed_2)));
+    assert_ne!(a, b);
+    assert_ne!(b, c);
+    assert_eq!(b, draw(&mut load_seeded(&module, Some(seed_1))));
+}
+
+#[test]
+fn seed_and_stream_are_snapshotted() {
+    let module = module(RANDOM);
+    let seed = derive_rng_seed(b"key", "box");
+
+    let mut original = load_seeded(&module, Some(seed));
+    draw(&mut original);
+    let snapshot = original.snapshot_state().unwrap();
+    let expected = draw(&mut original);
+
+    let mut restored = load_seeded(&module, None);
+    restored.restore_snapshot(&snapshot).unwrap();
+    assert_eq!(seed, restored.rng_seed());
+    assert_eq!(expected, draw(&mut restored));
+}
@@ -0,0 +1,66 @@
+mod common;
+
+use common::{load, module};
+use wasmbox_host::{WasmBoxError, WasmBoxHost, WasmBoxOptions};
+
+const REQUEST: &str = "request";
+const GROW: &str = "grow";
+
+fn pending_ids(host: &WasmBoxHost<u32, u32>) -> Vec<u32> {
+    host.pending_requests().iter().map(|r| r.id()).collect()
+}
+
+#[test]
+fn respond_to_requests() {
+    let mut host: WasmBoxHost<u32, u32> = load(&module(REQUEST), WasmBoxOptions::default());
+
+    host.message(&7).unwrap();
+    host.message(&8).unwrap();
+    assert_eq!(vec![7, 8], pending_ids(&host));
+    assert_eq!(7u32, host.pending_requests()[0].decode::<u32>().unwrap());
+    assert_eq!(None, host.next_output());
+
+    host.respond(8, &80u32).unwrap();
+    host.respond(7, &70u32).unwrap();
+    assert_eq!(vec![80, 70], host.drain_outputs().collect::<Vec<_>>());
+    assert!(host.pending_requests().is_empty());
+
+    assert!(matches!(
+        host.respond(7, &70u32),
+        Err(WasmBoxError::UnknownRequest(7))
+    ));
+}
+
+#[test]
+fn pending_requests_are_snapshotted() {
+    let mut host: WasmBoxHost<u32, u32> = load(&module(REQUEST), WasmBoxOptions::default());
+    host.message(&3).unwrap();
+    let snapshot = host.snapshot_state().unwrap();
+
+    host.respond(3, &30u32).unwrap();
+    assert!(host.pending_requests().is_empty());
+
+    host.restore_snapshot(&snapshot).unwrap();
+    assert_eq!(vec![3], pending_ids(&host));
+    host.respond(3, &31u32).unwrap();
+    assert_eq!(vec![30, 31], host.drain_outputs().collect::<Vec<_>>());
+}
+
+#[test]
+fn respond_without_export() {
+    let mut host: WasmBoxHost<u32, u32> = load(&module(GROW), WasmBoxOptions::default());
+
+    assert!(matches!(
+        host.respond(1, &0u32),
+        Err(WasmBoxError::MissingExport(_))
+    ));
+}
+
+#[test]
+fn refuse_duplicate_request_id() {
+    let mut host: WasmBoxHost<u32, u32> = load(&module(REQUEST), WasmBoxOptions::default());
+    host.message(&5).unwrap();
+
+    assert!(matches!(host.message(&5), Err(WasmBoxError::Abi(_))));
+    assert_eq!(vec![5], pending_ids(&host));
+}
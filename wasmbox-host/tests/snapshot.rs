@@ -0,0 +1,281 @@
+mod common;
+
+use common::{load_with_outputs, module, module_path, Outputs};
+use wasmbox_host::{
+    prepare_module, Compression, SnapshotOptions, WasmBoxError, WasmBoxHost, WasmBoxModule,
+    WasmBoxOptions,
+};
+
+const ACCUMULATOR: &str = "accumulator";
+const GROW: &str = "grow";
+const INIT_OUTPUT: &str = "init_output";
+const TABLE: &str = "table";
+
+fn load(name: &str) -> (WasmBoxHost<u32, u32>, Outputs<u32>) {
+    load_with_outputs(&module(name), WasmBoxOptions::default())
+}
+
+#[test]
+fn restore_into_fresh_host() {
+    let (mut original, original_outputs) = load(ACCUMULATOR);
+    original.message(&5).unwrap();
+    original.message(&7).unwrap();
+    assert_eq!(vec![5, 12], *original_outputs.lock().unwrap());
+
+    let snapshot = original.snapshot_state().unwrap();
+
+    let (mut restored, restored_outputs) = load(ACCUMULATOR);
+    restored.restore_snapshot(&snapshot).unwrap();
+    restored.message(&1).unwrap();
+    assert_eq!(vec![13], *restored_outputs.lock().unwrap());
+
+    // The original continues independently from the same point.
+    original.message(&2).unwrap();
+    assert_eq!(vec![5, 12, 14], *original_outputs.lock().unwrap());
+}
+
+#[test]
+fn restore_different_memory_size() {
+    let (mut original, _) = load(GROW);
+    let small = original.snapshot_state().unwrap();
+    original.message(&2).unwrap();
+    let large = original.snapshot_state().unwrap();
+
+    // Restoring a larger snapshot grows the memory to fit it.
+    let (mut restored, outputs) = load(GROW);
+    restored.restore_snapshot(&large).unwrap();
+    restored.message(&0).unwrap();
+    assert_eq!(vec![2], *outputs.lock().unwrap());
+
+    // Restoring a smaller snapshot zeroes the memory beyond it.
+    restored.restore_snapshot(&small).unwrap();
+    restored.message(&0).unwrap();
+    assert_eq!(vec![2, 0], *outputs.lock().unwrap());
+}
+
+#[test]
+fn restore_table_elements() {
+    let (mut original, outputs) = load(TABLE);
+    let initial = original.snapshot_state().unwrap();
+
+    // Elements written with table.set and table.grow are captured.
+    original.message(&1).unwrap();
+    original.message(&2).unwrap();
+    let grown = original.snapshot_state().unwrap();
+    original.message(&3).unwrap();
+    let cleared = original.snapshot_state().unwrap();
+    assert_eq!(vec![1002, 2032, 2030], *outputs.lock().unwrap());
+
+    let (mut restored, outputs) = load(TABLE);
+    restored.restore_snapshot(&grown).unwrap();
+    restored.message(&0).unwrap();
+    restored.restore_snapshot(&cleared).unwrap();
+    restored.message(&0).unwrap();
+
+    // Tables can't shrink, so restoring a smaller snapshot sets the elements beyond it to
+    // null.
+    restored.restore_snapshot(&initial).unwrap();
+    restored.message(&0).unwrap();
+    assert_eq!(vec![2032, 2030, 2001], *outputs.lock().unwrap());
+}
+
+#[test]
+fn incompatible_snapshot_leaves_box_unchanged() {
+    let (mut accumulator, _) = load(ACCUMULATOR);
+    accumulator.message(&3).unwrap();
+    let snapshot = accumulator.snapshot_state().unwrap();
+
+    // The accumulator has a global which the other module lacks, so the snapshot is refused
+    // before any of its memory is loaded.
+    let (mut other, outputs) = load(GROW);
+    other.message(&2).unwrap();
+    let error = other.restore_snapshot(&snapshot).unwrap_err();
+    assert!(matches!(error, WasmBoxError::IncompatibleSnapshot(_)));
+    assert!(!other.is_poisoned());
+
+    other.message(&0).unwrap();
+    assert_eq!(vec![2, 2], *outputs.lock().unwrap());
+}
+
+#[test]
+fn refuse_snapshot_file_from_other_module() {
+    let filename = std::env::temp_dir().join(format!("wasmbox-test-{}.bin", std::process::id()));
+    let filename = filename.to_str().unwrap();
+
+    let (mut accumulator, _) = load(ACCUMULATOR);
+    accumulator.message(&3).unwrap();
+    accumulator.snapshot_to_file(filename).unwrap();
+
+    let (mut other, _) = load(GROW);
+    let error = other.restore_snapshot_from_file(filename).unwrap_err();
+    assert!(matches!(error, WasmBoxError::IncompatibleSnapshot(_)));
+    assert!(error.to_string().contains("module"));
+
+    let (mut restored, outputs) = load(ACCUMULATOR);
+    restored.restore_snapshot_from_file(filename).unwrap();
+    restored.message(&1).unwrap();
+    assert_eq!(vec![4], *outputs.lock().unwrap());
+
+    std::fs::remove_file(filename).unwrap();
+}
+
+#[test]
+fn refuse_corrupt_snapshot_file() {
+    let filename = std::env::temp_dir().join(format!("wasmbox-test-{}-x.bin", std::process::id()));
+    let filename = filename.to_str().unwrap();
+
+    let (mut original, _) = load(ACCUMULATOR);
+    original.message(&3).unwrap();
+    original.snapshot_to_file(filename).unwrap();
+    let bytes = std::fs::read(filename).unwrap();
+
+    let mut corrupt = bytes.clone();
+    *corrupt.last_mut().unwrap() ^= 1;
+    std::fs::write(filename, &corrupt).unwrap();
+    let (mut restored, _) = load(ACCUMULATOR);
+    let error = restored.restore_snapshot_from_file(filename).unwrap_err();
+    assert!(matches!(error, WasmBoxError::IncompatibleSnapshot(_)));
+    assert!(error.to_string().contains("checksum"));
+
+    // The format version follows the 8-byte magic number.
+    let mut old_version = bytes;
+    old_version[8..12].copy_from_slice(&1u32.to_le_bytes());
+    std::fs::write(filename, &old_version).unwrap();
+    let error = restored.restore_snapshot_from_file(filename).unwrap_err();
+    assert!(matches!(error, WasmBoxError::IncompatibleSnapshot(_)));
+    assert!(error.to_string().contains("format version 1"));
+
+    std::fs::remove_file(filename).unwrap();
+}
+
+#[test]
+fn refuse_compressed_snapshot_larger_than_its_header() {
+    let filename = std::env::temp_dir().join(format!("wasmbox-test-{}-z.bin", std::process::id()));
+    let filename = filename.to_str().unwrap();
+
+    let (mut original, _) = load(ACCUMULATOR);
+    for compression in [Compression::Zstd, Compression::Lz4] {
+        let options = SnapshotOptions {
+            compression,
+            ..SnapshotOptions::default()
+        };
+        original
+            .snapshot_to_file_with_options(filename, &options)
+            .unwrap();
+
+        // The uncompressed size follows the magic number, format version, module hash,
+        // creation time and encoding. The checksum only covers the body, so it still matches.
+        let mut bytes = std::fs::read(filename).unwrap();
+        bytes[57..65].copy_from_slice(&16u64.to_le_bytes());
+        std::fs::write(filename, &bytes).unwrap();
+
+        let (mut restored, _) = load(ACCUMULATOR);
+        let error = restored.restore_snapshot_from_file(filename).unwrap_err();
+        assert!(matches!(error, WasmBoxError::IncompatibleSnapshot(_)));
+        assert!(error.to_string().contains("size"));
+    }
+
+    std::fs::remove_file(filename).unwrap();
+}
+
+#[test]
+fn share_snapshot_file_with_compiled_module() {
+    let filename = std::env::temp_dir().join(format!("wasmbox-test-{}-m.bin", std::process::id()));
+    let filename = filename.to_str().unwrap();
+    let compiled = std::env::temp_dir().join(format!("wasmbox-test-{}.cwasm", std::process::id()));
+    let compiled = compiled.to_str().unwrap();
+    prepare_module(&module_path(ACCUMULATOR), compiled).unwrap();
+
+    let (mut original, _) = load(ACCUMULATOR);
+    original.message(&3).unwrap();
+    original.snapshot_to_file(filename).unwrap();
+
+    let module = WasmBoxModule::from_compiled_module(compiled).unwrap();
+    let (mut restored, outputs): (WasmBoxHost<u32, u32>, _) =
+        load_with_outputs(&module, WasmBoxOptions::default());
+    restored.restore_snapshot_from_file(filename).unwrap();
+    restored.message(&1).unwrap();
+    assert_eq!(vec![4], *outputs.lock().unwrap());
+
+    std::fs::remove_file(filename).unwrap();
+    std::fs::remove_file(compiled).unwrap();
+}
+
+#[test]
+fn restore_chain_of_deltas() {
+    let (mut original, _) = load(ACCUMULATOR);
+    let base = original.snapshot_state().unwrap();
+
+    let mut checkpoint = base.clone();
+    let mut deltas = Vec::new();
+    for value in [4, 6] {
+        original.message(&value).unwrap();
+        let delta = original.snapshot_delta(&checkpoint).unwrap();
+        checkpoint.apply_delta(&delta).unwrap();
+        deltas.push(delta);
+    }
+
+    // The accumulator only writes to the first page of its memory.
+    assert!(deltas.iter().all(|delta| delta.changed_pages() == 1));
+
+    let (mut restored, outputs) = load(ACCUMULATOR);
+    restored
+        .restore_snapshot_with_deltas(&base, &deltas)
+        .unwrap();
+    restored.message(&1).unwrap();
+    assert_eq!(vec![11], *outputs.lock().unwrap());
+}
+
+#[test]
+fn compressed_and_sparse_snapshot_files() {
+    let filename = std::env::temp_dir().join(format!("wasmbox-test-{}-c.bin", std::process::id()));
+    let filename = filename.to_str().unwrap();
+
+    let (mut original, _) = load(ACCUMULATOR);
+    original.message(&8).unwrap();
+
+    for compression in [Compression::None, Compression::Zstd, Compression::Lz4] {
+        for elide_zero_pages in [false, true] {
+            let options = SnapshotOptions {
+                elide_zero_pages,
+                compression,
+            };
+            let stats = original
+                .snapshot_to_file_with_options(filename, &options)
+                .unwrap();
+            if elide_zero_pages || compression != Compression::None {
+                assert!(stats.compressed_bytes < stats.memory_bytes);
+            }
+
+            let (mut restored, outputs) = load(ACCUMULATOR);
+            restored.restore_snapshot_from_file(filename).unwrap();
+            restored.message(&1).unwrap();
+            assert_eq!(vec![9], *outputs.lock().unwrap());
+        }
+    }
+
+    std::fs::remove_file(filename).unwrap();
+}
+
+#[test]
+fn construct_from_snapshot_without_initializing() {
+    let (mut original, _) = load(INIT_OUTPUT);
+    original.message(&10).unwrap();
+    let snapshot = original.snapshot_state().unwrap();
+
+    let module = module(INIT_OUTPUT);
+    let outputs = Outputs::default();
+    let mut restored: WasmBoxHost<u32, u32> = {
+        let outputs = outputs.clone();
+        WasmBoxHost::from_snapshot(&module, &snapshot, move |value| {
+            outputs.lock().unwrap().push(value)
+        })
+        .unwrap()
+    };
+
+    // The guest's initialization would have sent its total.
+    assert!(outputs.lock().unwrap().is_empty());
+
+    restored.message(&1).unwrap();
+    assert_eq!(vec![11], *outputs.lock().unwrap());
+}
@@ -0,0 +1,63 @@
+mod common;
+
+use common::{load, module};
+use wasmbox_host::{WasmBoxError, WasmBoxHost, WasmBoxOptions};
+
+const TIMER: &str = "timer";
+const TIMER_WITHOUT_WAKE: &str = "timer_without_wake";
+
+#[test]
+fn advance_time_fires_due_timers() {
+    let module = module(TIMER);
+    let mut host: WasmBoxHost<u64, u32> = load(&module, WasmBoxOptions::default());
+    assert_eq!(None, host.next_timer_deadline());
+
+    host.message(&2_000).unwrap();
+    host.message(&1_500).unwrap();
+    assert_eq!(Some(1_500), host.next_timer_deadline());
+
+    host.advance_time(1_000).unwrap();
+    assert_eq!(None, host.next_output());
+
+    // Both timers are due, but the guest is only woken once.
+    host.advance_time(2_000).unwrap();
+    assert_eq!(vec![1], host.drain_outputs().collect::<Vec<_>>());
+    assert_eq!(None, host.next_timer_deadline());
+
+    // Setting the time doesn't wake the guest.
+    host.message(&3_000).unwrap();
+    host.set_time(4_000).unwrap();
+    assert_eq!(None, host.next_output());
+    host.advance_time(4_000).unwrap();
+    assert_eq!(vec![2], host.drain_outputs().collect::<Vec<_>>());
+}
+
+#[test]
+fn timers_survive_snapshots() {
+    let module = module(TIMER);
+    let mut host: WasmBoxHost<u64, u32> = load(&module, WasmBoxOptions::default());
+    host.message(&1_000).unwrap();
+    let snapshot = host.snapshot_state().unwrap();
+
+    let mut restored: WasmBoxHost<u64, u32> = load(&module, WasmBoxOptions::default());
+    restored.restore_snapshot(&snapshot).unwrap();
+    assert_eq!(Some(1_000), restored.next_timer_deadline());
+    restored.advance_time(1_000).unwrap();
+    assert_eq!(vec![1], restored.drain_outputs().collect::<Vec<_>>());
+}
+
+#[test]
+fn advance_time_without_wake_export() {
+    let module = module(TIMER_WITHOUT_WAKE);
+    let mut host: WasmBoxHost<u64, ()> = load(&module, WasmBoxOptions::default());
+    host.message(&1_000).unwrap();
+    let hash = host.state_hash().unwrap();
+
+    // The clock isn't moved when the guest can't be woken for the timer that is due.
+    assert!(matches!(
+        host.advance_time(1_000),
+        Err(WasmBoxError::MissingExport(_))
+    ));
+    assert_eq!(hash, host.state_hash().unwrap());
+    assert_eq!(Some(1_000), host.next_timer_deadline());
+}
@@ -0,0 +1,95 @@
+//! A single-threaded executor which runs the tasks of an asynchronous guest. The guest only
+//! runs while the host calls into it, so rather than running forever, the executor polls
+//! the tasks which have been woken until none of them can make progress, and then returns to
+//! the host.
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Wake, Waker},
+};
+
+type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+thread_local! {
+    /// Tasks passed to `spawn` which the executor has not yet taken.
+    static SPAWNED: RefCell<Vec<Task>> = RefCell::default();
+
+    /// Tasks which have been woken since they were last polled, by index.
+    static WOKEN: RefCell<VecDeque<usize>> = RefCell::default();
+}
+
+/// Run `future` as a task of its own, concurrently with the task which spawned it.
+pub fn spawn(future: impl Future<Output = ()> + 'static) {
+    SPAWNED.with(|spawned| spawned.borrow_mut().push(Box::pin(future)));
+}
+
+/// Wakes a task by queueing its index to be polled.
+struct TaskWaker(usize);
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        WOKEN.with(|woken| woken.borrow_mut().push_back(self.0));
+    }
+}
+
+#[derive(Default)]
+pub struct Executor {
+    /// Tasks by index. The slots of finished tasks are `None` until they are reused.
+    tasks: Vec<Option<Task>>,
+}
+
+impl Executor {
+    /// Add a task, to be polled the next time the executor runs.
+    pub fn insert(&mut self, task: Task) {
+        let index = match self.tasks.iter().position(Option::is_none) {
+            Some(index) => {
+                self.tasks[index] = Some(task);
+                index
+            }
+            None => {
+                self.tasks.push(Some(task));
+                self.tasks.len() - 1
+            }
+        };
+        WOKEN.with(|woken| woken.borrow_mut().push_back(index));
+    }
+
+    /// Poll woken tasks, including newly spawned ones, until none are left.
+    pub fn run_until_stalled(&mut self) {
+        loop {
+            for task in SPAWNED.with(|spawned| spawned.take()) {
+                self.insert(task);
+            }
+
+            let index = match WOKEN.with(|woken| woken.borrow_mut().pop_front()) {
+                Some(index) => index,
+                None => break,
+            };
+            // The task may have finished since it was woken.
+            let task = match self.tasks.get_mut(index) {
+                Some(Some(task)) => task,
+                _ => continue,
+            };
+
+            let waker = Waker::from(Arc::new(TaskWaker(index)));
+            if task
+                .as_mut()
+                .poll(&mut Context::from_waker(&waker))
+                .is_ready()
+            {
+                self.tasks[index] = None;
+            }
+        }
+    }
+
+    /// Drop every task, including those spawned but not yet taken.
+    pub fn clear(&mut self) {
+        self.tasks.clear();
+        SPAWNED.with(|spawned| spawned.take());
+        WOKEN.with(|woken| woken.take());
+    }
+}
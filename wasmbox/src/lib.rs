@@ -1,19 +1,26 @@
 #![doc = include_str!("../README.md")]
 
+mod executor;
 pub mod prelude;
 pub mod wasm;
 
 use async_trait::async_trait;
+use executor::Executor;
 use serde::{de::DeserializeOwned, Serialize};
 use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
     future::Future,
     marker::PhantomData,
     pin::Pin,
     rc::Rc,
-    sync::mpsc::{channel, Receiver, Sender, TryRecvError},
+    sync::Arc,
     task::{Context, Poll, Waker},
+    time::{Duration, SystemTime},
 };
 
+pub use executor::spawn;
+
 /// WASM is single-threaded, so we can safely ignore Send requirements.
 #[derive(Clone)]
 struct IgnoreSend<T>(pub T);
@@ -21,45 +28,271 @@ unsafe impl<T> Send for IgnoreSend<T> {}
 unsafe impl<T> Sync for IgnoreSend<T> {}
 
 pub trait WasmBox: 'static {
-    type Input: Serialize;
-    type Output: DeserializeOwned;
+    type Input: DeserializeOwned;
+    type Output: Serialize;
 
     fn init(callback: Box<dyn Fn(Self::Output) + Send + Sync>) -> Self
     where
         Self: Sized;
 
     fn message(&mut self, input: Self::Input);
+
+    /// Called when the host responds to a request made with `WasmBoxContext::request`, after
+    /// the response has been stored for the request's future to pick up.
+    fn responded(&mut self) {}
+
+    /// Called when the host's clock reaches the deadline of a timer set with
+    /// `WasmBoxContext::sleep` or `sleep_until`.
+    fn woken(&mut self) {}
+
+    /// Once the box has finished, the value it finished with, serialized. Boxes which never
+    /// finish return `None`.
+    fn exit_value(&self) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// Requests made with `WasmBoxContext::request`, and the responses the host has delivered
+/// which are not yet taken by their futures.
+#[derive(Default)]
+struct Requests {
+    next_id: u32,
+    responses: HashMap<u32, Vec<u8>>,
+    /// Wakers of the tasks waiting for responses, by request id.
+    waiting: HashMap<u32, Waker>,
+}
+
+/// Timers set by `SleepFuture`s which are still waiting.
+#[derive(Default)]
+struct Timers {
+    next_id: u64,
+    /// The deadline of each timer, and the waker of the task waiting for it, by timer id.
+    pending: HashMap<u64, (u64, Waker)>,
+}
+
+thread_local! {
+    static REQUESTS: RefCell<Requests> = RefCell::default();
+    static TIMERS: RefCell<Timers> = RefCell::default();
+}
+
+/// Store the host's response to a request, to be taken by the request's future, and wake the
+/// task waiting for it.
+fn store_response(id: u32, response: Vec<u8>) {
+    let waker = REQUESTS.with(|requests| {
+        let mut requests = requests.borrow_mut();
+        requests.responses.insert(id, response);
+        requests.waiting.remove(&id)
+    });
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+/// Wake the tasks waiting for timers whose deadlines have passed. Each timer stays pending
+/// until its `SleepFuture` sees that it has ended.
+fn wake_due_timers() {
+    let now = now_millis();
+    let due: Vec<Waker> = TIMERS.with(|timers| {
+        timers
+            .borrow()
+            .pending
+            .values()
+            .filter(|(deadline, _)| *deadline <= now)
+            .map(|(_, waker)| waker.clone())
+            .collect()
+    });
+    due.into_iter().for_each(Waker::wake);
+}
+
+/// Resolves to the host's response to a request made with `WasmBoxContext::request`. The
+/// request is sent to the host when the future is first polled, and cancelled if the future is
+/// dropped before its response arrives.
+pub struct ResponseFuture<Response> {
+    /// The serialized request, until it has been sent.
+    request: Option<Vec<u8>>,
+    /// Id of the request sent to the host, once it has been sent.
+    id: Option<u32>,
+    _ph_response: PhantomData<Response>,
+}
+
+// The response is only ever deserialized, never stored, so the future can be moved freely.
+impl<Response> Unpin for ResponseFuture<Response> {}
+
+impl<Response: DeserializeOwned> Future for ResponseFuture<Response> {
+    type Output = Response;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Response> {
+        if let Some(request) = self.request.take() {
+            let id = REQUESTS.with(|requests| {
+                let mut requests = requests.borrow_mut();
+                requests.next_id = requests.next_id.wrapping_add(1);
+                requests.next_id
+            });
+            wasm::request(id, &request);
+            self.id = Some(id);
+        }
+
+        let id = self.id.expect("ResponseFuture polled after it resolved.");
+        let response = REQUESTS.with(|requests| {
+            let mut requests = requests.borrow_mut();
+            let response = requests.responses.remove(&id);
+            if response.is_none() {
+                requests.waiting.insert(id, cx.waker().clone());
+            }
+            response
+        });
+        match response {
+            Some(response) => {
+                self.id = None;
+                Poll::Ready(bincode::deserialize(&response).expect("Error deserializing."))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<Response> Drop for ResponseFuture<Response> {
+    /// Forget the request, if it was sent and is still waiting, and tell the host that its
+    /// response is no longer wanted.
+    fn drop(&mut self) {
+        if let Some(id) = self.id.take() {
+            let responded = REQUESTS.with(|requests| {
+                let mut requests = requests.borrow_mut();
+                requests.waiting.remove(&id);
+                requests.responses.remove(&id).is_some()
+            });
+            if !responded {
+                wasm::cancel_request(id);
+            }
+        }
+    }
+}
+
+/// The host's clock, in milliseconds since the Unix epoch.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
+/// Resolves once the host's clock reaches a deadline. Returned by `WasmBoxContext::sleep` and
+/// `sleep_until`. Its timer is cancelled once it resolves, or if it is dropped before then.
+pub struct SleepFuture {
+    /// In milliseconds since the Unix epoch.
+    deadline: u64,
+    /// Id of the timer set with the host, once it has been set.
+    timer: Option<u64>,
+}
+
+impl Future for SleepFuture {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if now_millis() >= self.deadline {
+            self.cancel_timer();
+            return Poll::Ready(());
+        }
+
+        if self.timer.is_none() {
+            wasm::set_timer(self.deadline);
+            let id = TIMERS.with(|timers| {
+                let mut timers = timers.borrow_mut();
+                let id = timers.next_id;
+                timers.next_id += 1;
+                timers
+                    .pending
+                    .insert(id, (self.deadline, cx.waker().clone()));
+                id
+            });
+            self.timer = Some(id);
+        }
+        Poll::Pending
+    }
+}
+
+impl SleepFuture {
+    /// Forget the timer, if it was set, and tell the host that it is no longer wanted.
+    fn cancel_timer(&mut self) {
+        if let Some(id) = self.timer.take() {
+            TIMERS.with(|timers| timers.borrow_mut().pending.remove(&id));
+            wasm::cancel_timer(self.deadline);
+        }
+    }
+}
+
+impl Drop for SleepFuture {
+    fn drop(&mut self) {
+        self.cancel_timer();
+    }
+}
+
+/// Messages delivered to an asynchronous guest which no task has taken yet, and the tasks
+/// waiting for one.
+struct Inbox<Input> {
+    messages: VecDeque<Input>,
+    waiting: Vec<Waker>,
+}
+
+impl<Input> Inbox<Input> {
+    fn new() -> Self {
+        Inbox {
+            messages: VecDeque::new(),
+            waiting: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, message: Input) {
+        self.messages.push_back(message);
+        self.waiting.drain(..).for_each(Waker::wake);
+    }
 }
 
 pub struct NextMessageFuture<Input> {
-    _ph_output: PhantomData<Input>,
-    queue: IgnoreSend<Rc<Receiver<Input>>>,
+    inbox: IgnoreSend<Rc<RefCell<Inbox<Input>>>>,
 }
 
 impl<Input> Future for NextMessageFuture<Input> {
     type Output = Input;
 
-    fn poll(self: Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> std::task::Poll<Input> {
-        match self.queue.0.try_recv() {
-            Ok(value) => Poll::Ready(value),
-            Err(TryRecvError::Empty) => Poll::Pending,
-            _ => panic!("Queue became disconnected."),
+    fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Input> {
+        let mut inbox = self.inbox.0.borrow_mut();
+        match inbox.messages.pop_front() {
+            Some(value) => Poll::Ready(value),
+            None => {
+                if !inbox
+                    .waiting
+                    .iter()
+                    .any(|waker| waker.will_wake(cx.waker()))
+                {
+                    inbox.waiting.push(cx.waker().clone());
+                }
+                Poll::Pending
+            }
         }
     }
 }
 
+/// Handle through which an asynchronous guest interacts with the host. Clones share the same
+/// host connection and message queue, so that tasks spawned with `spawn` can have their own.
 pub struct WasmBoxContext<Input, Output> {
-    callback: Box<dyn Fn(Output) + Send + Sync>,
-    queue: IgnoreSend<Rc<Receiver<Input>>>,
-    _ph_o: PhantomData<Output>,
+    callback: Arc<dyn Fn(Output) + Send + Sync>,
+    inbox: IgnoreSend<Rc<RefCell<Inbox<Input>>>>,
+}
+
+impl<Input, Output> Clone for WasmBoxContext<Input, Output> {
+    fn clone(&self) -> Self {
+        WasmBoxContext {
+            callback: self.callback.clone(),
+            inbox: self.inbox.clone(),
+        }
+    }
 }
 
 impl<Input, Output> WasmBoxContext<Input, Output> {
-    fn new(callback: Box<dyn Fn(Output) + Send + Sync>, receiver: Receiver<Input>) -> Self {
+    fn new(callback: Box<dyn Fn(Output) + Send + Sync>, inbox: Rc<RefCell<Inbox<Input>>>) -> Self {
         WasmBoxContext {
-            callback,
-            queue: IgnoreSend(Rc::new(receiver)),
-            _ph_o: PhantomData::default(),
+            callback: Arc::from(callback),
+            inbox: IgnoreSend(inbox),
         }
     }
 
@@ -67,74 +300,117 @@ impl<Input, Output> WasmBoxContext<Input, Output> {
         (self.callback)(output);
     }
 
-    pub fn next(&self) -> NextMessageFuture<Input> {
-        NextMessageFuture {
-            _ph_output: PhantomData::default(),
-            queue: self.queue.clone(),
-        }
+    /// Call the function registered on the host under `name`, and wait for its response.
+    pub fn call_host<Request, Response>(&self, name: &str, request: &Request) -> Response
+    where
+        Request: Serialize,
+        Response: DeserializeOwned,
+    {
+        wasm::call_host(name, request)
     }
-}
-
-#[async_trait]
-pub trait AsyncWasmBox: 'static + Sized {
-    type Input: Serialize;
-    type Output: DeserializeOwned;
-
-    async fn run(ctx: WasmBoxContext<Self::Input, Self::Output>) -> ();
-}
-
-mod dummy_context {
-    use std::{
-        ptr,
-        task::{RawWaker, RawWakerVTable, Waker},
-    };
 
-    type WakerData = *const ();
+    /// Send a request to the host, and wait for the host to respond with
+    /// `WasmBoxHost::respond`. Other messages continue to be delivered in the meantime.
+    ///
+    /// The request is sent when the returned future is first polled. Dropping the future
+    /// before the host responds cancels the request.
+    ///
+    /// # Panics
+    ///
+    /// The future panics if the host's response can't be deserialized as `Response`.
+    pub fn request<Request, Response>(&self, request: &Request) -> ResponseFuture<Response>
+    where
+        Request: Serialize,
+        Response: DeserializeOwned,
+    {
+        let request = bincode::serialize(request).expect("Error serializing.");
 
-    unsafe fn clone(_: WakerData) -> RawWaker {
-        raw_waker()
+        ResponseFuture {
+            request: Some(request),
+            id: None,
+            _ph_response: PhantomData,
+        }
     }
-    unsafe fn wake(_: WakerData) {
-        panic!("Should never wake dummy waker!")
+
+    /// Wait until `duration` has passed on the host's clock. The host wakes the guest when it
+    /// advances its clock past the deadline with `WasmBoxHost::advance_time`.
+    pub fn sleep(&self, duration: Duration) -> SleepFuture {
+        // Round up, so that the sleep lasts at least `duration`.
+        let millis = duration.as_nanos().div_ceil(1_000_000) as u64;
+        self.sleep_until_millis(now_millis().saturating_add(millis))
     }
-    unsafe fn wake_by_ref(_: WakerData) {
-        panic!("Should never wake dummy waker!")
+
+    /// Wait until the host's clock reaches `deadline`.
+    pub fn sleep_until(&self, deadline: SystemTime) -> SleepFuture {
+        let deadline = deadline
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_nanos().div_ceil(1_000_000) as u64);
+        self.sleep_until_millis(deadline)
     }
-    unsafe fn drop(_: WakerData) {}
 
-    static MY_VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop);
+    fn sleep_until_millis(&self, deadline: u64) -> SleepFuture {
+        SleepFuture {
+            deadline,
+            timer: None,
+        }
+    }
 
-    fn raw_waker() -> RawWaker {
-        RawWaker::new(ptr::null(), &MY_VTABLE)
+    /// Wait for the next message from the host. If several tasks are waiting, each message
+    /// goes to only one of them.
+    pub fn next(&self) -> NextMessageFuture<Input> {
+        NextMessageFuture {
+            inbox: self.inbox.clone(),
+        }
     }
 
-    pub fn waker() -> Waker {
-        unsafe { Waker::from_raw(raw_waker()) }
+    /// Run `future` as a task of its own, concurrently with the task which spawned it. The
+    /// task runs until it completes, or until `AsyncWasmBox::run` returns.
+    pub fn spawn(&self, future: impl Future<Output = ()> + 'static) {
+        spawn(future)
     }
 }
 
+#[async_trait]
+pub trait AsyncWasmBox: 'static + Sized {
+    type Input: DeserializeOwned;
+    type Output: Serialize;
+    /// The type `run` returns, which is passed to the host when the box finishes.
+    type Exit: Serialize;
+
+    async fn run(ctx: WasmBoxContext<Self::Input, Self::Output>) -> Self::Exit;
+}
+
 pub struct AsyncWasmBoxBox<B>
 where
     B: AsyncWasmBox,
 {
-    future: Pin<Box<dyn Future<Output = ()>>>,
-    sender: Sender<B::Input>,
+    executor: Executor,
+    inbox: Rc<RefCell<Inbox<B::Input>>>,
+    /// The serialized value `run` returned, set once it returns. After that, the box ignores
+    /// messages.
+    exit_value: Rc<RefCell<Option<Vec<u8>>>>,
     _ph_b: PhantomData<B>,
-    waker: Waker,
 }
 
 impl<B> AsyncWasmBoxBox<B>
 where
     B: AsyncWasmBox,
 {
+    fn is_finished(&self) -> bool {
+        self.exit_value.borrow().is_some()
+    }
+
     fn poll(&mut self) {
-        match self
-            .future
-            .as_mut()
-            .poll(&mut Context::from_waker(&self.waker))
-        {
-            Poll::Ready(_) => panic!("Function exited."),
-            Poll::Pending => (),
+        if self.is_finished() {
+            return;
+        }
+
+        wake_due_timers();
+        self.executor.run_until_stalled();
+
+        if self.is_finished() {
+            // Tasks spawned by `run` don't outlive it.
+            self.executor.clear();
         }
     }
 }
@@ -147,16 +423,25 @@ where
     type Output = B::Output;
 
     fn init(callback: Box<dyn Fn(B::Output) + Send + Sync>) -> Self {
-        let (sender, recv) = channel();
-        let ctx = WasmBoxContext::new(callback, recv);
-        let future = B::run(ctx);
-        let waker = dummy_context::waker();
+        let inbox = Rc::new(RefCell::new(Inbox::new()));
+        let ctx = WasmBoxContext::new(callback, inbox.clone());
+        let exit_value = Rc::new(RefCell::new(None));
+        let main = {
+            let exit_value = exit_value.clone();
+            async move {
+                let value = B::run(ctx).await;
+                let value = bincode::serialize(&value).expect("Error serializing.");
+                exit_value.replace(Some(value));
+            }
+        };
+        let mut executor = Executor::default();
+        executor.insert(Box::pin(main));
 
         let mut async_box = AsyncWasmBoxBox {
-            future,
-            sender,
-            waker,
-            _ph_b: PhantomData::default(),
+            executor,
+            inbox,
+            exit_value,
+            _ph_b: PhantomData,
         };
 
         async_box.poll();
@@ -164,8 +449,23 @@ where
     }
 
     fn message(&mut self, input: Self::Input) {
-        self.sender.send(input).expect("Error sending message.");
+        if self.is_finished() {
+            return;
+        }
+        self.inbox.borrow_mut().push(input);
+
+        self.poll();
+    }
+
+    fn responded(&mut self) {
+        self.poll();
+    }
 
+    fn woken(&mut self) {
         self.poll();
     }
+
+    fn exit_value(&self) -> Option<Vec<u8>> {
+        self.exit_value.borrow().clone()
+    }
 }
@@ -1,63 +1,233 @@
-use crate::{AsyncWasmBox, AsyncWasmBoxBox, WasmBox};
-use std::cell::RefCell;
+use crate::{store_response, AsyncWasmBox, AsyncWasmBoxBox, WasmBox};
+use serde::{de::DeserializeOwned, Serialize};
+use std::cell::{Cell, RefCell};
 
 extern crate alloc;
 
+/// Type-erased view of a `WasmBox`, which takes its input in serialized form so that
+/// boxes with any `Input` and `Output` types can be stored in `WASM_BOX`.
+trait SerializedWasmBox {
+    fn message_serialized(&mut self, message: &[u8]);
+
+    fn responded(&mut self);
+
+    fn woken(&mut self);
+
+    fn exit_value(&self) -> Option<Vec<u8>>;
+}
+
+impl<B> SerializedWasmBox for B
+where
+    B: WasmBox,
+{
+    fn message_serialized(&mut self, message: &[u8]) {
+        let message: B::Input = bincode::deserialize(message).expect("Error deserializing.");
+        self.message(message)
+    }
+
+    fn responded(&mut self) {
+        WasmBox::responded(self)
+    }
+
+    fn woken(&mut self) {
+        WasmBox::woken(self)
+    }
+
+    fn exit_value(&self) -> Option<Vec<u8>> {
+        WasmBox::exit_value(self)
+    }
+}
+
 thread_local! {
-    static WASM_BOX: RefCell<Option<Box<dyn WasmBox<Input = String, Output = String>>>> = RefCell::default();
+    static WASM_BOX: RefCell<Option<Box<dyn SerializedWasmBox>>> = RefCell::default();
+
+    /// Whether the box's exit value has been passed to the host.
+    static EXIT_REPORTED: Cell<bool> = Cell::default();
 }
 
 extern "C" {
     /// Send a message from the wasm module to the host.
     pub fn wasmbox_callback(message_ptr: u32, message_len: u32);
+
+    /// Call a function registered on the host. Returns the address of the response in the
+    /// high 32 bits and its length in the low 32 bits; the response must be released with
+    /// `wasmbox_free`.
+    pub fn wasmbox_call_host(
+        name_ptr: u32,
+        name_len: u32,
+        request_ptr: u32,
+        request_len: u32,
+    ) -> u64;
+
+    /// Send a request to the host, which it will respond to later by calling
+    /// `wasmbox_respond` with the same `id`. The host traps if `id` is already the id of a
+    /// request it has not responded to.
+    pub fn wasmbox_request(id: u32, request_ptr: u32, request_len: u32);
+
+    /// Tell the host that the module no longer wants the response to the request sent with
+    /// `wasmbox_request` as `id`, so that it is no longer outstanding.
+    pub fn wasmbox_cancel_request(id: u32);
+
+    /// Ask the host to call `wasmbox_wake` once its clock reaches `deadline`, in milliseconds
+    /// since the Unix epoch.
+    pub fn wasmbox_timer(deadline: u64);
+
+    /// Tell the host that a timer set with `wasmbox_timer` for `deadline` has fired, or is no
+    /// longer wanted, so that it is not counted by `WasmBoxHost::next_timer_deadline`.
+    pub fn wasmbox_cancel_timer(deadline: u64);
+
+    /// Tell the host that the module has finished, with the given serialized exit value.
+    pub fn wasmbox_exit(exit_value_ptr: u32, exit_value_len: u32);
 }
 
-pub fn wrapped_callback(message: String) {
+pub fn wrapped_callback<Output>(message: Output)
+where
+    Output: Serialize,
+{
     let message = bincode::serialize(&message).expect("Error serializing.");
     unsafe {
-        wasmbox_callback(&message[0] as *const u8 as u32, message.len() as u32);
+        wasmbox_callback(message.as_ptr() as u32, message.len() as u32);
     }
 }
 
+/// Call the function registered on the host under `name`, and wait for its response.
+pub fn call_host<Request, Response>(name: &str, request: &Request) -> Response
+where
+    Request: Serialize,
+    Response: DeserializeOwned,
+{
+    let request = bincode::serialize(request).expect("Error serializing.");
+    let response = unsafe {
+        wasmbox_call_host(
+            name.as_ptr() as u32,
+            name.len() as u32,
+            request.as_ptr() as u32,
+            request.len() as u32,
+        )
+    };
+
+    let (ptr, len) = ((response >> 32) as u32, response as u32);
+    if len == 0 {
+        return bincode::deserialize(&[]).expect("Error deserializing.");
+    }
+
+    let response = unsafe { std::slice::from_raw_parts(ptr as *const u8, len as usize) };
+    let response = bincode::deserialize(response).expect("Error deserializing.");
+    unsafe { wasmbox_free(ptr as *mut u8, len) };
+
+    response
+}
+
+/// Send a serialized request made with `WasmBoxContext::request` to the host.
+pub fn request(id: u32, request: &[u8]) {
+    unsafe {
+        wasmbox_request(id, request.as_ptr() as u32, request.len() as u32);
+    }
+}
+
+/// Tell the host that the response to a request sent with `request` is no longer wanted.
+pub fn cancel_request(id: u32) {
+    unsafe { wasmbox_cancel_request(id) }
+}
+
+/// Ask the host to wake the module once its clock reaches `deadline`, in milliseconds since
+/// the Unix epoch.
+pub fn set_timer(deadline: u64) {
+    unsafe { wasmbox_timer(deadline) }
+}
+
+/// Tell the host that a timer set with `set_timer` has fired, or is no longer wanted.
+pub fn cancel_timer(deadline: u64) {
+    unsafe { wasmbox_cancel_timer(deadline) }
+}
+
+/// Tell the host if the box has finished, unless it has been told already.
+fn report_exit(wasm_box: &dyn SerializedWasmBox) {
+    if EXIT_REPORTED.with(Cell::get) {
+        return;
+    }
+
+    if let Some(exit_value) = wasm_box.exit_value() {
+        unsafe { wasmbox_exit(exit_value.as_ptr() as u32, exit_value.len() as u32) };
+        EXIT_REPORTED.with(|reported| reported.set(true));
+    }
+}
+
+/// Call into the box with `f`, then tell the host if the box finished.
+fn with_box(uninitialized_error: &str, f: impl FnOnce(&mut dyn SerializedWasmBox)) {
+    WASM_BOX.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let wasm_box = cell.as_mut().expect(uninitialized_error);
+        f(wasm_box.as_mut());
+        report_exit(wasm_box.as_ref());
+    });
+}
+
 pub fn initialize<B>()
 where
-    B: WasmBox<Input = String, Output = String>,
+    B: WasmBox,
 {
-    let wasm_box = B::init(Box::new(wrapped_callback));
+    let wasm_box = B::init(Box::new(wrapped_callback::<B::Output>));
+    report_exit(&wasm_box);
     WASM_BOX.with(|cell| cell.replace(Some(Box::new(wasm_box))));
 }
 
 pub fn initialize_async<B>()
 where
-    B: AsyncWasmBox<Input = String, Output = String>,
+    B: AsyncWasmBox,
 {
-    let wasm_box: AsyncWasmBoxBox<B> = AsyncWasmBoxBox::init(Box::new(wrapped_callback));
+    let wasm_box: AsyncWasmBoxBox<B> =
+        AsyncWasmBoxBox::init(Box::new(wrapped_callback::<B::Output>));
+    report_exit(&wasm_box);
     WASM_BOX.with(|cell| cell.replace(Some(Box::new(wasm_box))));
 }
 
 #[no_mangle]
 extern "C" fn wasmbox_send(ptr: *const u8, len: usize) {
-    let message: String = unsafe {
-        let bytes = std::slice::from_raw_parts(ptr, len).to_vec();
-        bincode::deserialize(&bytes).expect("Error deserializing.")
-    };
+    let message = unsafe { std::slice::from_raw_parts(ptr, len) };
 
-    WASM_BOX.with(|cell| {
-        cell.borrow_mut()
-            .as_mut()
-            .expect("Received message before initialized.")
-            .message(message)
+    with_box("Received message before initialized.", |wasm_box| {
+        wasm_box.message_serialized(message)
     });
 }
 
+/// Receive the host's response to a request, and resume the code waiting for it.
+#[no_mangle]
+extern "C" fn wasmbox_respond(id: u32, ptr: *const u8, len: usize) {
+    let response = unsafe { std::slice::from_raw_parts(ptr, len) };
+    store_response(id, response.to_vec());
+
+    with_box("Received response before initialized.", |wasm_box| {
+        wasm_box.responded()
+    });
+}
+
+/// Called by the host when the deadline of a timer set with `wasmbox_timer` has passed.
+#[no_mangle]
+extern "C" fn wasmbox_wake() {
+    with_box("Woken before initialized.", |wasm_box| wasm_box.woken());
+}
+
+/// Allocate a buffer in the module's memory, used by the host to pass messages in.
+///
+/// # Safety
+///
+/// Only intended to be called by the host. The returned buffer must be released with
+/// `wasmbox_free` using the same size.
 #[no_mangle]
 pub unsafe extern "C" fn wasmbox_malloc(size: u32) -> *mut u8 {
-    let layout = core::alloc::Layout::from_size_align_unchecked(size as usize, 0);
+    let layout = core::alloc::Layout::from_size_align_unchecked(size as usize, 1);
     alloc::alloc::alloc(layout)
 }
 
+/// Release a buffer allocated with `wasmbox_malloc`.
+///
+/// # Safety
+///
+/// `ptr` must have been returned by `wasmbox_malloc` with the same `size`, and must not be
+/// used afterwards.
 #[no_mangle]
 pub unsafe extern "C" fn wasmbox_free(ptr: *mut u8, size: u32) {
-    let layout = core::alloc::Layout::from_size_align_unchecked(size as usize, 0);
+    let layout = core::alloc::Layout::from_size_align_unchecked(size as usize, 1);
     alloc::alloc::dealloc(ptr, layout);
 }
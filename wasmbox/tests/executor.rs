@@ -0,0 +1,50 @@
+//! Runs an asynchronous guest natively, without a host, to test the executor.
+
+use std::sync::{Arc, Mutex};
+use wasmbox::prelude::*;
+use wasmbox::AsyncWasmBoxBox;
+
+/// Echoes messages until it receives zero, and then returns their total. A spawned task
+/// announces itself first.
+struct Echo;
+
+#[async_trait::async_trait]
+impl AsyncWasmBox for Echo {
+    type Input = u32;
+    type Output = (&'static str, u32);
+    type Exit = u32;
+
+    async fn run(ctx: WasmBoxContext<u32, (&'static str, u32)>) -> u32 {
+        let mut total = 0;
+        let spawned = ctx.clone();
+        ctx.spawn(async move { spawned.send(("spawned", 0)) });
+
+        loop {
+            let value = ctx.next().await;
+            if value == 0 {
+                return total;
+            }
+            total += value;
+            ctx.send(("echo", value));
+        }
+    }
+}
+
+#[test]
+fn run_returns_cleanly() {
+    let outputs = Arc::new(Mutex::new(Vec::new()));
+    let mut echo: AsyncWasmBoxBox<Echo> = {
+        let outputs = outputs.clone();
+        AsyncWasmBoxBox::init(Box::new(move |output| outputs.lock().unwrap().push(output)))
+    };
+
+    // Messages after `run` returns are ignored.
+    for value in [1, 2, 0, 3] {
+        echo.message(value);
+    }
+    assert_eq!(
+        vec![("spawned", 0), ("echo", 1), ("echo", 2)],
+        *outputs.lock().unwrap()
+    );
+    assert_eq!(Some(bincode::serialize(&3u32).unwrap()), echo.exit_value());
+}
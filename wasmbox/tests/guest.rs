@@ -0,0 +1,212 @@
+//! Runs a guest built with this crate for wasm32 (the crate in `tests/guest`) on a real host,
+//! to test the guest side of the ABI.
+
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::process::Command as Process;
+use std::sync::OnceLock;
+use wasmbox_host::{HostFunctions, WasmBoxHost, WasmBoxModule, WasmBoxOptions};
+
+/// Must match `Command` in `tests/guest/src/lib.rs`.
+#[derive(Serialize)]
+enum Command {
+    CallHost(String),
+    Request(u32),
+    AbandonRequest(u32),
+    Sleep(u64),
+    AbandonSleep(u64),
+    RequestThenSleep(u32),
+    Exit(u32),
+}
+
+/// Must match `Event` in `tests/guest/src/lib.rs`.
+#[derive(Deserialize, Debug, PartialEq)]
+enum Event {
+    Called(String),
+    Responded(u32),
+    Slept(u64),
+    RequestedThenSlept(u32),
+}
+
+/// Build the guest for wasm32, once for all the tests, and return the path of its module.
+fn guest_wasm() -> &'static str {
+    static WASM: OnceLock<String> = OnceLock::new();
+    WASM.get_or_init(|| {
+        let manifest = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/guest/Cargo.toml");
+        let target_dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("guest");
+        let status = Process::new(env!("CARGO"))
+            .args(["build", "--release", "--target", "wasm32-wasip1"])
+            .arg("--manifest-path")
+            .arg(manifest)
+            .arg("--target-dir")
+            .arg(&target_dir)
+            .status()
+            .expect("Error running cargo.");
+        assert!(status.success(), "Error building the guest.");
+
+        target_dir
+            .join("wasm32-wasip1/release/wasmbox_test_guest.wasm")
+            .to_str()
+            .unwrap()
+            .to_string()
+    })
+}
+
+fn load(options: WasmBoxOptions) -> WasmBoxHost<Command, Event> {
+    let module = WasmBoxModule::from_wasm_file(guest_wasm()).unwrap();
+    WasmBoxHost::new_with_output_queue(&module, options).unwrap()
+}
+
+fn pending_requests(host: &WasmBoxHost<Command, Event>) -> Vec<(u32, u32)> {
+    host.pending_requests()
+        .iter()
+        .map(|request| (request.id(), request.decode().unwrap()))
+        .collect()
+}
+
+#[test]
+fn call_host_function() {
+    let mut host_functions = HostFunctions::default();
+    host_functions.register("greet", |name: String| format!("Hello, {}!", name));
+    let mut host = load(WasmBoxOptions {
+        host_functions,
+        ..WasmBoxOptions::default()
+    });
+
+    // A response longer than the request, to check that the guest reads the length the host
+    // packed with the pointer.
+    host.message(&Command::CallHost("guest".into())).unwrap();
+    assert_eq!(
+        vec![Event::Called("Hello, guest!".into())],
+        host.drain_outputs().collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn request_resumes_when_host_responds() {
+    let mut host = load(WasmBoxOptions::default());
+
+    host.message(&Command::Request(7)).unwrap();
+    host.message(&Command::Request(8)).unwrap();
+    let requests = pending_requests(&host);
+    assert_eq!(vec![7, 8], requests.iter().map(|r| r.1).collect::<Vec<_>>());
+    assert_eq!(None, host.next_output());
+
+    // The guest is suspended in the middle of its request, so the snapshot carries both the
+    // waiting task and the outstanding request.
+    let snapshot = host.snapshot_state().unwrap();
+    host.respond(requests[1].0, &80u32).unwrap();
+    host.respond(requests[0].0, &70u32).unwrap();
+    assert_eq!(
+        vec![Event::Responded(80), Event::Responded(70)],
+        host.drain_outputs().collect::<Vec<_>>()
+    );
+    assert!(host.pending_requests().is_empty());
+
+    let mut restored = load(WasmBoxOptions::default());
+    restored.restore_snapshot(&snapshot).unwrap();
+    assert_eq!(requests, pending_requests(&restored));
+    restored.respond(requests[0].0, &71u32).unwrap();
+    assert_eq!(Some(Event::Responded(71)), restored.next_output());
+}
+
+#[test]
+fn abandoned_request_is_cancelled() {
+    let mut host = load(WasmBoxOptions::default());
+
+    host.message(&Command::Request(7)).unwrap();
+    host.message(&Command::AbandonRequest(8)).unwrap();
+    let requests = pending_requests(&host);
+    assert_eq!(vec![7], requests.iter().map(|r| r.1).collect::<Vec<_>>());
+
+    host.respond(requests[0].0, &70u32).unwrap();
+    assert_eq!(Some(Event::Responded(70)), host.next_output());
+    assert!(host.pending_requests().is_empty());
+}
+
+#[test]
+fn sleep_until_host_advances_time() {
+    let mut host = load(WasmBoxOptions::default());
+    host.set_time(1_000).unwrap();
+
+    host.message(&Command::Sleep(500)).unwrap();
+    host.message(&Command::Sleep(500)).unwrap();
+    host.message(&Command::Sleep(800)).unwrap();
+    assert_eq!(Some(1_500), host.next_timer_deadline());
+    let snapshot = host.snapshot_state().unwrap();
+
+    host.advance_time(1_200).unwrap();
+    assert_eq!(None, host.next_output());
+
+    // Both sleeps with the same deadline end.
+    host.advance_time(1_500).unwrap();
+    assert_eq!(
+        vec![Event::Slept(500), Event::Slept(500)],
+        host.drain_outputs().collect::<Vec<_>>()
+    );
+    assert_eq!(Some(1_800), host.next_timer_deadline());
+
+    let mut restored = load(WasmBoxOptions::default());
+    restored.restore_snapshot(&snapshot).unwrap();
+    assert_eq!(Some(1_500), restored.next_timer_deadline());
+    restored.advance_time(2_000).unwrap();
+    assert_eq!(3, restored.drain_outputs().count());
+    assert_eq!(None, restored.next_timer_deadline());
+}
+
+#[test]
+fn timers_which_fire_during_a_message_are_cancelled() {
+    let mut host = load(WasmBoxOptions::default());
+    host.set_time(1_000).unwrap();
+    host.message(&Command::Sleep(500)).unwrap();
+
+    // The guest sees that the sleep is over when it handles the next message, without being
+    // woken for it.
+    host.set_time(2_000).unwrap();
+    host.message(&Command::Sleep(1_000)).unwrap();
+    assert_eq!(Some(Event::Slept(500)), host.next_output());
+    assert_eq!(Some(3_000), host.next_timer_deadline());
+}
+
+#[test]
+fn abandoned_sleep_is_cancelled() {
+    let mut host = load(WasmBoxOptions::default());
+    host.set_time(1_000).unwrap();
+
+    host.message(&Command::AbandonSleep(500)).unwrap();
+    assert_eq!(None, host.next_timer_deadline());
+}
+
+#[test]
+fn spawned_tasks_are_woken_independently() {
+    let mut host = load(WasmBoxOptions::default());
+    host.set_time(1_000).unwrap();
+
+    host.message(&Command::RequestThenSleep(5)).unwrap();
+    host.message(&Command::Sleep(100)).unwrap();
+    host.message(&Command::Request(6)).unwrap();
+    let requests = pending_requests(&host);
+    assert_eq!(vec![5, 6], requests.iter().map(|r| r.1).collect::<Vec<_>>());
+    assert_eq!(Some(1_100), host.next_timer_deadline());
+
+    // The first task goes back to sleep once it has its response, and only the task waiting
+    // for the second request is woken by the response to it.
+    host.respond(requests[0].0, &300u32).unwrap();
+    assert_eq!(None, host.next_output());
+    host.respond(requests[1].0, &60u32).unwrap();
+    assert_eq!(Some(Event::Responded(60)), host.next_output());
+
+    host.advance_time(1_100).unwrap();
+    assert_eq!(Some(Event::Slept(100)), host.next_output());
+    assert_eq!(Some(1_300), host.next_timer_deadline());
+    host.advance_time(1_300).unwrap();
+    assert_eq!(Some(Event::RequestedThenSlept(300)), host.next_output());
+    assert_eq!(None, host.next_timer_deadline());
+
+    // Tasks still waiting when `run` returns are dropped with it.
+    host.message(&Command::Sleep(100)).unwrap();
+    host.message(&Command::Exit(9)).unwrap();
+    assert_eq!(Some(9), host.exit_value::<u32>().unwrap());
+    assert_eq!(None, host.next_timer_deadline());
+    assert_eq!(None, host.next_output());
+}
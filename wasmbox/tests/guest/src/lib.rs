@@ -0,0 +1,83 @@
+//! A guest built with the `wasmbox` crate, which `tests/guest.rs` compiles to wasm32 and runs
+//! on a real host to test the guest side of the ABI.
+
+use serde::{Deserialize, Serialize};
+use std::task::{Context, Waker};
+use std::time::Duration;
+use wasmbox::prelude::*;
+
+/// Must match `Command` in `tests/guest.rs`.
+#[derive(Deserialize)]
+pub enum Command {
+    /// Call the host function "greet" with the name.
+    CallHost(String),
+    /// Request the value from the host in a task of its own, and send the response.
+    Request(u32),
+    /// Request the value from the host, and give up straight away.
+    AbandonRequest(u32),
+    /// Sleep for the number of milliseconds in a task of its own, and then send it back.
+    Sleep(u64),
+    /// Start sleeping for the number of milliseconds, and give up straight away.
+    AbandonSleep(u64),
+    /// In a task of its own, request the value from the host, sleep for as many milliseconds
+    /// as the response, and then send the response back.
+    RequestThenSleep(u32),
+    /// Return from `run` with the value.
+    Exit(u32),
+}
+
+/// Must match `Event` in `tests/guest.rs`.
+#[derive(Serialize)]
+pub enum Event {
+    Called(String),
+    Responded(u32),
+    Slept(u64),
+    RequestedThenSlept(u32),
+}
+
+#[wasmbox]
+async fn run(ctx: WasmBoxContext<Command, Event>) -> u32 {
+    loop {
+        match ctx.next().await {
+            Command::CallHost(name) => {
+                let greeting: String = ctx.call_host("greet", &name);
+                ctx.send(Event::Called(greeting));
+            }
+            Command::Request(value) => {
+                let task_ctx = ctx.clone();
+                ctx.spawn(async move {
+                    let response = task_ctx.request(&value).await;
+                    task_ctx.send(Event::Responded(response));
+                });
+            }
+            Command::AbandonRequest(value) => {
+                // Polling the request once sends it.
+                let mut request = Box::pin(ctx.request::<_, u32>(&value));
+                let _ = request
+                    .as_mut()
+                    .poll(&mut Context::from_waker(Waker::noop()));
+            }
+            Command::Sleep(millis) => {
+                let task_ctx = ctx.clone();
+                ctx.spawn(async move {
+                    task_ctx.sleep(Duration::from_millis(millis)).await;
+                    task_ctx.send(Event::Slept(millis));
+                });
+            }
+            Command::AbandonSleep(millis) => {
+                // Polling the sleep once sets its timer.
+                let mut sleep = Box::pin(ctx.sleep(Duration::from_millis(millis)));
+                let _ = sleep.as_mut().poll(&mut Context::from_waker(Waker::noop()));
+            }
+            Command::RequestThenSleep(value) => {
+                let task_ctx = ctx.clone();
+                ctx.spawn(async move {
+                    let response: u32 = task_ctx.request(&value).await;
+                    task_ctx.sleep(Duration::from_millis(response.into())).await;
+                    task_ctx.send(Event::RequestedThenSlept(response));
+                });
+            }
+            Command::Exit(value) => return value,
+        }
+    }
+}
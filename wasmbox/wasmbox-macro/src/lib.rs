@@ -2,7 +2,41 @@ extern crate proc_macro;
 use proc_macro::TokenStream;
 use proc_macro2::Ident;
 use quote::quote;
-use syn::{ItemEnum, ItemFn, ItemStruct, ItemType, ReturnType};
+use syn::{
+    FnArg, GenericArgument, ItemEnum, ItemFn, ItemStruct, ItemType, PathArguments, ReturnType, Type,
+};
+
+/// Extract the `Input` and `Output` types from an argument of type `WasmBoxContext<Input, Output>`.
+fn get_context_types(arg: &FnArg) -> Option<(Type, Type)> {
+    let ty = match arg {
+        FnArg::Typed(pat_type) => &*pat_type.ty,
+        FnArg::Receiver(_) => return None,
+    };
+
+    let segment = match ty {
+        Type::Path(type_path) => type_path.path.segments.last()?,
+        _ => return None,
+    };
+
+    if segment.ident != "WasmBoxContext" {
+        return None;
+    }
+
+    let args = match &segment.arguments {
+        PathArguments::AngleBracketed(args) => &args.args,
+        _ => return None,
+    };
+
+    let mut types = args.iter().filter_map(|arg| match arg {
+        GenericArgument::Type(ty) => Some(ty.clone()),
+        _ => None,
+    });
+
+    match (types.next(), types.next(), types.next()) {
+        (Some(input), Some(output), None) => Some((input, output)),
+        _ => None,
+    }
+}
 
 fn wasmbox_impl(item: &proc_macro2::TokenStream) -> proc_macro2::TokenStream {
     let func: ItemFn = syn::parse2(item.clone()).expect("#[wasmbox] should annotate a function.");
@@ -15,16 +49,20 @@ fn wasmbox_impl(item: &proc_macro2::TokenStream) -> proc_macro2::TokenStream {
         panic!("#[wasmbox] should annotate an async function.");
     }
 
-    if let ReturnType::Default = func.sig.output {
-    } else {
-        panic!("The function wrapped by #[wasmbox] should not have a return type.");
-    }
+    let exit_type = match &func.sig.output {
+        ReturnType::Default => quote! { () },
+        ReturnType::Type(_, ty) => quote! { #ty },
+    };
 
     let inputs: Vec<_> = func.sig.inputs.iter().collect();
     if inputs.len() != 1 {
         panic!("The function wrapped by #[wasmbox] should have exactly one argument (a WasmboxContext.)");
     }
 
+    let (input_type, output_type) = get_context_types(inputs[0]).expect(
+        "The argument of the function wrapped by #[wasmbox] should be a WasmBoxContext<Input, Output>.",
+    );
+
     let inputs = func.sig.inputs;
     let block = func.block;
 
@@ -40,10 +78,11 @@ fn wasmbox_impl(item: &proc_macro2::TokenStream) -> proc_macro2::TokenStream {
             struct WasmBoxImpl;
 
             impl AsyncWasmBox for WasmBoxImpl {
-                type Input = String;
-                type Output = String;
+                type Input = #input_type;
+                type Output = #output_type;
+                type Exit = #exit_type;
 
-                fn run<'async_trait>(#inputs) -> Pin<Box<dyn Future<Output = ()> + Send + 'async_trait>> where
+                fn run<'async_trait>(#inputs) -> Pin<Box<dyn Future<Output = Self::Exit> + Send + 'async_trait>> where
                     Self: 'async_trait
                 {
                     Box::pin(async move {
@@ -75,7 +114,7 @@ fn get_name(item: &proc_macro2::TokenStream) -> Option<Ident> {
 }
 
 fn wasmbox_sync_impl(item: &proc_macro2::TokenStream) -> proc_macro2::TokenStream {
-    let ident: Ident = get_name(&item)
+    let ident: Ident = get_name(item)
         .expect("Item decorated by #[wasmbox_sync] should be a struct, enum, or type.");
 
     quote! {